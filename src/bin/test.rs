@@ -1,31 +1,40 @@
-use std::error;
-use std::fs::File;
-use std::io::{Cursor, Read};
+use std::{env, error};
 use ntfs::KnownNtfsFileRecordNumber::{RootDirectory};
-use win_dedupe::{VolumeReader, VolumeIndexFlatArray};
+use win_dedupe::{is_mft_dump, CompactVolumeIndex, open_mft_dump, open_volume, VolumeIndexFlatArray, VolumeSource};
 
 fn main() -> Result<(), Box<dyn error::Error>> {
-    // println!("Opening MFT dump: \"./c.MFT\"...");
-    //
-    // let mut file = File::open("./c.MFT")?;
-    // let mut buf = vec![0u8; file.metadata()?.len() as usize];
-    // file.read(&mut buf)?;
-    // let mut reader = Cursor::new(buf);
+    // Accepts a drive letter, a raw image path or an extracted $MFT file, defaulting to the system volume
+    let path = env::args().nth(1).unwrap_or_else(|| String::from(r"\\.\C:"));
+    let mut reader = open_volume(&path)?;
 
-    let path = r"\\.\C:";
-    println!("Opening raw volume: \"{}\"...", path);
-    let mut reader = VolumeReader::open_path(path)?;
-    println!("Reading file metadata and building index...");
-    let index = VolumeIndexFlatArray::from_volume_reader(&mut reader, None)?;
+    let index = if is_mft_dump(&mut reader) {
+        println!("Opening MFT dump: \"{}\"...", path);
+        let mut reader = open_mft_dump(&path)?;
+        println!("Reading file metadata and building index...");
+        VolumeIndexFlatArray::from_mft_reader(&mut reader, None)?
+    } else {
+        println!("Opening raw volume: \"{}\"...", path);
+        println!("Reading file metadata and building index...");
+        let index = VolumeIndexFlatArray::from_volume_reader(&mut reader, None)?;
+        if let Some(stats) = reader.cache_stats() {
+            println!("Volume cache: {:?}", stats);
+        }
+        index
+    };
     println!("Building tree...");
-    let index = index.build_tree();
+    let (index, report) = index.build_tree();
+    for anomaly in &report.anomalies {
+        println!("[WARN] {}", anomaly.description());
+    }
+
+    let tree_memory_usage = index.memory_usage();
+    let index = CompactVolumeIndex::from_tree(index);
+    println!("Index memory usage: {} bytes as a tree, {} bytes compacted", tree_memory_usage, index.memory_usage());
 
     if let Some(children) = index.dir_children(RootDirectory as usize) {
         println!("Root directory has children");
         for c in children {
-            if let Some(f) = &index.0[*c as usize] {
-                println!("{}", f.name.as_ref().unwrap());
-            }
+            println!("{}", index.link_name(c.index, c.link));
         }
     }
 
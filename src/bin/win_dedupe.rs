@@ -1,11 +1,7 @@
-#![feature(int_roundings)]
-#![feature(iter_collect_into)]
-#![feature(try_blocks)]
-
 use cursive::event::Event;
 use cursive::theme::{BorderStyle, Palette};
 use cursive::traits::With;
-use cursive::views::{Button, Dialog, DummyView, LinearLayout, ProgressBar, ScrollView, SelectView, TextView};
+use cursive::views::{Button, Dialog, DummyView, LinearLayout, OnEventView, ProgressBar, ScrollView, SelectView, TextView};
 use cursive::{Cursive, CursiveExt};
 
 use clap::Parser;
@@ -14,27 +10,48 @@ use cursive::view::Resizable;
 
 use std::*;
 use std::cmp::Ordering;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
 use cursive::utils::Counter;
 use ntfs::KnownNtfsFileRecordNumber::RootDirectory;
 use num_format::{Locale, ToFormattedString};
-use win_dedupe::{FileMetadata, get_mft_entry_count, VolumeIndexFlatArray, VolumeIndexTree, VolumeReader};
+use win_dedupe::{CompactVolumeIndex, IndexCacheKey, ORPHANS_DIRECTORY_NAME, TreeBuildReport, get_mft_entry_count, get_mft_reader_entry_count, is_mft_dump, open_mft_dump, open_volume, parse_drive_letter, read_partitions, verify_ntfs_system_id, Partition, PartitionReader, Timestamps, VolumeIndexFlatArray, VolumeSource};
 use anyhow::Result;
 use cursive::view::Nameable;
 use cursive_table_view::{TableView, TableViewItem};
 
+#[cfg(windows)]
 use winsafe::{GetLogicalDriveStrings, GetVolumeInformation};
 
 /// Search for a pattern in a file and display the lines that contain it.
 #[derive(Parser)]
 struct Cli {
+    /// Drive letter (e.g. C:), a VHD/VHDX file, an extracted $MFT file, or path to a raw NTFS volume image (.img/.dd, or the first .001 segment of a split image)
     path: Option<String>,
+    /// Partition number to scan when the path is a whole disk
+    #[arg(long)]
+    partition: Option<u32>,
+    /// Scan the volume even if its index is cached and up to date
+    #[arg(long)]
+    rescan: bool,
 }
 
 #[derive(Default)]
 struct UserData {
-    index: Option<VolumeIndexTree>,
+    index: Option<CompactVolumeIndex>,
+    // Of the tree as built and of the compact index it was turned into, in bytes
+    tree_memory_usage: usize,
+    index_memory_usage: usize,
     dir_stack: Vec<usize>,
-    drive_letter: char,
+    volume_name: String,
+    // False when the index was loaded from a $MFT dump, which has no file contents to hash or preview
+    has_volume_data: bool,
+    // Hidden files are left out of the explorer unless toggled on, like in Windows Explorer
+    show_hidden: bool,
+    tree_report: TreeBuildReport,
+    rescan: bool,
+    loaded_from_cache: bool,
 }
 
 fn main() -> Result<()> {
@@ -73,8 +90,12 @@ fn main() -> Result<()> {
     });
 
     let args = Cli::parse();
+    get_user_data(&mut siv).rescan = args.rescan;
     if let Some(path) = args.path {
-        explore_a_volume_loading(&mut siv, &path);
+        match args.partition {
+            Some(number) => explore_a_partition_number_loading(&mut siv, &path, number),
+            None => explore_a_volume_loading(&mut siv, &path),
+        }
     } else {
         let buttons = LinearLayout::vertical()
             .child(TextView::new(
@@ -103,6 +124,7 @@ Select an option:"
 fn deduplicate_files_menu(_s: &mut Cursive) {}
 
 
+#[cfg(windows)]
 fn explore_volumes_menu(s: &mut Cursive) {
     let mut select = SelectView::<String>::new().on_submit(explore_a_volume_loading);
 
@@ -117,19 +139,193 @@ fn explore_volumes_menu(s: &mut Cursive) {
         }
     }
 
+    // Whole disks, so that partitions without a drive letter can be scanned too
+    for n in 0.. {
+        let path = format!(r"\\.\PhysicalDrive{}", n);
+        if open_volume(&path).is_err() {
+            break;
+        }
+        select.add_item(format!("{} - Whole disk", path), path);
+    }
+
     s.pop_layer();
     s.add_layer(Dialog::around(select).title("Select a Volume"));
 }
 
+#[cfg(not(windows))]
+fn explore_volumes_menu(s: &mut Cursive) {
+    s.pop_layer();
+    s.add_layer(
+        Dialog::text("Listing mounted volumes is only supported on Windows.")
+            .title("Select a Volume")
+            .button("Quit", Cursive::quit),
+    );
+}
+
+fn display_name(path: &str) -> String {
+    match parse_drive_letter(path) {
+        Some(drive_letter) => format!("{}:", drive_letter),
+        None => std::path::Path::new(path)
+            .file_name()
+            .map_or_else(|| path.to_string(), |n| n.to_string_lossy().into_owned()),
+    }
+}
+
+// Anything that stops a volume from being scanned at all
+fn cannot_scan_dialog(s: &mut Cursive, message: String) {
+    s.pop_layer();
+    s.add_layer(
+        Dialog::text(message)
+            .title("Cannot Scan")
+            .button("Quit", Cursive::quit),
+    );
+}
+
 fn explore_a_volume_loading(s: &mut Cursive, path: &str) {
-    let drive_letter = path.chars().nth(0).unwrap();
-    assert!(drive_letter.is_alphabetic());
-    assert_eq!(path.chars().nth(1).unwrap(), ':');
+    let mut reader = match open_volume(path) {
+        Ok(reader) => reader,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot open {}: {:#}", display_name(path), e)),
+    };
+
+    if is_mft_dump(&mut reader) {
+        scan_mft_dump_loading(s, path);
+        return;
+    }
+
+    // Whole disks (disk images, \\.\PhysicalDriveN) don't start with an NTFS boot sector, let the user pick a partition
+    if !verify_ntfs_system_id(&mut reader) {
+        match read_partitions(&mut reader) {
+            Ok(partitions) => select_partition_menu(s, path, partitions),
+            Err(e) => cannot_scan_dialog(s, format!("Cannot read the partition table of {}: {:#}", display_name(path), e)),
+        }
+        return;
+    }
+
+    scan_volume_loading(s, display_name(path), reader);
+}
+
+fn select_partition_menu(s: &mut Cursive, path: &str, partitions: Vec<Partition>) {
+    if partitions.is_empty() {
+        cannot_scan_dialog(s, format!("{} is neither an NTFS volume nor a partitioned disk.", display_name(path)));
+        return;
+    }
+
+    s.pop_layer();
+
+    let disk_path = path.to_string();
+    let mut select = SelectView::<Partition>::new().on_submit(move |s, p: &Partition| {
+        if p.is_ntfs {
+            explore_a_partition_loading(s, &disk_path, p);
+        }
+    });
+
+    for p in partitions {
+        let mut label = format!(
+            "Partition {} - {} - {} MB at offset {}",
+            p.number,
+            p.partition_type.description(),
+            (p.length / 1024 / 1024).to_formatted_string(&Locale::en),
+            p.offset.to_formatted_string(&Locale::en),
+        );
+        if let Some(name) = &p.name {
+            label.push_str(&format!(" - {}", name));
+        }
+        if !p.is_ntfs {
+            label.push_str(" - Not NTFS, cannot scan");
+        }
+        select.add_item(label, p);
+    }
+
+    s.add_layer(Dialog::around(ScrollView::new(select)).title(format!("Select a Partition on {}", display_name(path))));
+}
+
+fn explore_a_partition_number_loading(s: &mut Cursive, path: &str, number: u32) {
+    let partitions = match open_volume(path) {
+        Ok(mut disk) => read_partitions(&mut disk),
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot open {}: {:#}", display_name(path), e)),
+    };
+    let partitions = match partitions {
+        Ok(partitions) => partitions,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot read the partition table of {}: {:#}", display_name(path), e)),
+    };
+
+    match partitions.iter().find(|p| p.number == number) {
+        Some(p) => explore_a_partition_loading(s, path, p),
+        None => select_partition_menu(s, path, partitions),
+    }
+}
+
+fn explore_a_partition_loading(s: &mut Cursive, path: &str, partition: &Partition) {
+    let disk = match open_volume(path) {
+        Ok(disk) => disk,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot open {}: {:#}", display_name(path), e)),
+    };
+    let reader = Box::new(PartitionReader::from_partition(disk, partition));
 
-    let path = format!(r"\\.\{}:", drive_letter);
-    let mut reader = VolumeReader::open_path(&path).unwrap();
-    let entry_count = get_mft_entry_count(&mut reader).unwrap();
+    scan_volume_loading(s, format!("{} partition {}", display_name(path), partition.number), reader);
+}
+
+fn scan_volume_loading(s: &mut Cursive, volume_name: String, mut reader: Box<dyn VolumeSource + Send>) {
+    // A volume whose $MFT can't be read fails to scan below, with the reason
+    let cache_key = IndexCacheKey::read(&mut reader).ok();
+    if let (Some(key), false) = (cache_key, get_user_data(s).rescan) {
+        if let Some(index) = load_cached_index(&mut reader, &key) {
+            let u = get_user_data(s);
+            u.volume_name = volume_name;
+            u.has_volume_data = true;
+            u.loaded_from_cache = true;
+            build_tree_loading_screen(s, index);
+            return;
+        }
+    }
+
+    let entry_count = match get_mft_entry_count(&mut reader) {
+        Ok(count) => count,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot read the $MFT of {}: {:#}", volume_name, e)),
+    };
+
+    index_loading(s, volume_name, entry_count, true, move |counter| {
+        let index = VolumeIndexFlatArray::from_volume_reader(&mut reader, Some(counter))?;
+        // Volumes without a USN journal aren't cached, as there'd be no telling whether the cache is up to date
+        let journal = index.journal_position(&mut reader).ok().flatten();
+        if let (Some(key), Some(journal)) = (cache_key, journal) {
+            // Failing to write the cache only means scanning again next time
+            let _ = index.save_cache(&key, journal);
+        }
+        Ok(index)
+    });
+}
+
+// A cached index brought up to date from the USN journal, which finds nothing to do if the volume hasn't changed
+fn load_cached_index<R: VolumeSource>(reader: &mut R, key: &IndexCacheKey) -> Option<VolumeIndexFlatArray> {
+    let mut cached = VolumeIndexFlatArray::load_cache(key).ok()??;
+    let journal = cached.index.update_from_journal(reader, cached.journal).ok()??;
+    if journal != cached.journal {
+        let _ = cached.index.save_cache(key, journal);
+    }
+    Some(cached.index)
+}
+
+fn scan_mft_dump_loading(s: &mut Cursive, path: &str) {
+    let mut reader = match open_mft_dump(path) {
+        Ok(reader) => reader,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot open {}: {:#}", display_name(path), e)),
+    };
+    let entry_count = match get_mft_reader_entry_count(&mut reader) {
+        Ok(count) => count,
+        Err(e) => return cannot_scan_dialog(s, format!("Cannot read {}: {:#}", display_name(path), e)),
+    };
+
+    // A $MFT dump has every file's metadata but none of their contents
+    index_loading(s, display_name(path), entry_count, false, move |counter| {
+        VolumeIndexFlatArray::from_mft_reader(&mut reader, Some(counter))
+    });
+}
 
+fn index_loading<F>(s: &mut Cursive, volume_name: String, entry_count: u64, has_volume_data: bool, load: F)
+    where
+        F: FnOnce(Arc<AtomicUsize>) -> Result<VolumeIndexFlatArray> + Send + 'static,
+{
     s.set_autorefresh(true);
 
     let cb = s.cb_sink().clone();
@@ -145,11 +341,20 @@ fn explore_a_volume_loading(s: &mut Cursive, path: &str) {
             .title("Please Wait"),
     );
 
-    get_user_data(s).drive_letter = drive_letter;
+    let u = get_user_data(s);
+    u.volume_name = volume_name;
+    u.has_volume_data = has_volume_data;
 
     thread::spawn(move || {
-        let index = VolumeIndexFlatArray::from_volume_reader(&mut reader, Some(counter.0)).unwrap();
-        cb.send(Box::new(|s| build_tree_loading_screen(s, index))).unwrap();
+        let index = load(counter.0);
+        cb.send(Box::new(|s| match index {
+            Ok(index) => build_tree_loading_screen(s, index),
+            Err(e) => {
+                s.set_autorefresh(false);
+                let message = format!("Cannot read the $MFT of {}: {:#}", get_user_data(s).volume_name, e);
+                cannot_scan_dialog(s, message);
+            }
+        })).unwrap();
     });
 }
 
@@ -160,7 +365,14 @@ fn build_tree_loading_screen(s: &mut Cursive, index: VolumeIndexFlatArray) {
             .title("Please Wait"),
     );
 
-    get_user_data(s).index = Some(index.build_tree());
+    let (tree, report) = index.build_tree();
+    let tree_memory_usage = tree.memory_usage();
+    let index = CompactVolumeIndex::from_tree(tree);
+    let u = get_user_data(s);
+    u.tree_memory_usage = tree_memory_usage;
+    u.index_memory_usage = index.memory_usage();
+    u.index = Some(index);
+    u.tree_report = report;
     s.cb_sink().send(Box::new(finished_loading)).unwrap();
 }
 
@@ -178,25 +390,86 @@ fn finished_loading(s: &mut Cursive) {
 enum ExploreVolumeColumn {
     Name,
     Size,
+    Modified,
+    Created,
+    Accessed,
+    MftModified,
+}
+
+// What the table shows of a file, copied out of the index so that filling the table doesn't rebuild every file
+#[derive(Clone)]
+struct ExploreRow {
+    inode: usize,
+    // The file's name in the directory being explored, as hard links have a name per directory
+    name: String,
+    is_dir: bool,
+    // Directories show the size of everything in them
+    size: u64,
+    // From $STANDARD_INFORMATION, as Windows shows them
+    times: Timestamps,
+    link_target: Option<String>,
+    is_placeholder: bool,
+    link_count: usize,
+    stream_count: usize,
+    pop_from_stack: bool,
+}
+
+impl ExploreRow {
+    fn new(index: &CompactVolumeIndex, inode: usize, name: &str, pop_from_stack: bool) -> Self {
+        let is_dir = index.is_dir(inode);
+        ExploreRow {
+            inode,
+            name: String::from(name),
+            is_dir,
+            size: if is_dir { index.totals(inode).size } else { index.file_size(inode) },
+            times: index.standard_information_times(inode),
+            link_target: index.reparse_point(inode).and_then(|r| r.target.clone()),
+            is_placeholder: index.is_placeholder(inode),
+            link_count: index.link_count(inode),
+            stream_count: index.streams(inode).count(),
+            pop_from_stack,
+        }
+    }
 }
 
-impl TableViewItem<ExploreVolumeColumn> for (FileMetadata, bool) {
+impl TableViewItem<ExploreVolumeColumn> for ExploreRow {
     fn to_column(&self, column: ExploreVolumeColumn) -> String {
-        let (f, pop_from_stack) = self;
         match column {
             ExploreVolumeColumn::Name => {
-                if *pop_from_stack {
+                if self.pop_from_stack {
                     String::from("↩️ ../")
                 } else {
-                    let name = f.name.clone().unwrap();
-                    if f.is_dir {
-                        format!("📁 {name}/")
+                    let name = &self.name;
+                    let mut text = if self.link_target.is_some() {
+                        format!("🔗 {name}")
+                    } else if self.is_placeholder {
+                        format!("☁️ {name}")
+                    } else if self.is_dir {
+                        format!("📁 {name}")
                     } else {
                         format!("📄 {name}")
+                    };
+
+                    if self.is_dir {
+                        text.push('/');
+                    }
+                    if let Some(target) = &self.link_target {
+                        text.push_str(&format!(" → {target}"));
                     }
+                    if self.link_count > 1 {
+                        text.push_str(&format!(" ({} links)", self.link_count));
+                    }
+                    if self.stream_count > 0 {
+                        text.push_str(&format!(" (+{} streams)", self.stream_count));
+                    }
+                    text
                 }
             }
-            ExploreVolumeColumn::Size => self.0.file_size.to_string(),
+            ExploreVolumeColumn::Size => self.size.to_string(),
+            ExploreVolumeColumn::Modified => self.times.modified.to_string(),
+            ExploreVolumeColumn::Created => self.times.created.to_string(),
+            ExploreVolumeColumn::Accessed => self.times.accessed.to_string(),
+            ExploreVolumeColumn::MftModified => self.times.mft_modified.to_string(),
         }
     }
 
@@ -204,73 +477,203 @@ impl TableViewItem<ExploreVolumeColumn> for (FileMetadata, bool) {
         where
             Self: Sized,
     {
-        if self.1 { return Ordering::Less; }
-        if other.1 { return Ordering::Greater; }
+        if self.pop_from_stack { return Ordering::Less; }
+        if other.pop_from_stack { return Ordering::Greater; }
 
+        let (a, b) = (self, other);
         match column {
             ExploreVolumeColumn::Name => {
-                if self.0.is_dir && !other.0.is_dir { return Ordering::Less; }
-                if !self.0.is_dir && other.0.is_dir { return Ordering::Greater; }
+                if a.is_dir && !b.is_dir { return Ordering::Less; }
+                if !a.is_dir && b.is_dir { return Ordering::Greater; }
 
-                self.0.name.as_ref().unwrap().to_lowercase().cmp(&other.0.name.as_ref().unwrap().to_lowercase())
+                self.name.to_lowercase().cmp(&other.name.to_lowercase())
             }
             ExploreVolumeColumn::Size => {
-                other.0.file_size.cmp(&self.0.file_size)
+                b.size.cmp(&a.size)
+            }
+            ExploreVolumeColumn::Modified => {
+                b.times.modified.cmp(&a.times.modified)
+            }
+            ExploreVolumeColumn::Created => {
+                b.times.created.cmp(&a.times.created)
+            }
+            ExploreVolumeColumn::Accessed => {
+                b.times.accessed.cmp(&a.times.accessed)
+            }
+            ExploreVolumeColumn::MftModified => {
+                b.times.mft_modified.cmp(&a.times.mft_modified)
             }
         }
     }
 }
 
 fn explore_a_volume_screen(s: &mut Cursive) {
-    let mut table = TableView::<(FileMetadata, bool), ExploreVolumeColumn>::new()
+    let mut table = TableView::<ExploreRow, ExploreVolumeColumn>::new()
         .column(ExploreVolumeColumn::Name, "Name", |c| {
-            c.width_percent(80)
+            c.width_percent(32)
         })
         .column(ExploreVolumeColumn::Size, "Size", |c| {
             c.ordering(Ordering::Greater)
-                .width_percent(20)
+                .width_percent(12)
+        })
+        .column(ExploreVolumeColumn::Modified, "Modified", |c| {
+            c.width_percent(14)
+        })
+        .column(ExploreVolumeColumn::Created, "Created", |c| {
+            c.width_percent(14)
+        })
+        .column(ExploreVolumeColumn::Accessed, "Accessed", |c| {
+            c.width_percent(14)
+        })
+        // When the file's MFT record last changed, e.g. by a rename or a change of attributes
+        .column(ExploreVolumeColumn::MftModified, "MFT Changed", |c| {
+            c.width_percent(14)
         });
 
     let u = get_user_data(s);
     let parent_inode = u.dir_stack.last().unwrap();
     let index = u.index.as_ref().unwrap();
 
-    for i in index.dir_children(*parent_inode).unwrap() {
-        let i = *i as usize;
-        table.insert_item((index.0[i].clone().unwrap(), false));
+    for c in index.dir_children(*parent_inode).unwrap() {
+        if index.attributes(c.index).is_hidden() && !u.show_hidden {
+            continue;
+        }
+        table.insert_item(ExploreRow::new(index, c.index, index.link_name(c.index, c.link), false));
     }
 
     if let Some(i) = u.dir_stack.iter().rev().nth(1) {
-        table.insert_item_at(0, (index.0[*i].clone().unwrap(), true));
+        table.insert_item_at(0, ExploreRow::new(index, *i, "", true));
     }
 
     table.set_on_submit(|s, _row, index| {
-        let (f, pop_from_stack) = s
-            .call_on_name("table", |table: &mut TableView<(FileMetadata, bool), ExploreVolumeColumn>| {
+        let row = s
+            .call_on_name("table", |table: &mut TableView<ExploreRow, ExploreVolumeColumn>| {
                 table.borrow_item(index).unwrap().clone()
             })
             .unwrap();
 
         let u = get_user_data(s);
-        if pop_from_stack {
+        if row.pop_from_stack {
             u.dir_stack.pop();
-        } else if f.is_dir {
-            u.dir_stack.push(f.index as usize);
+        } else if row.is_dir && row.link_target.is_none() {
+            u.dir_stack.push(row.inode);
+        } else {
+            // Links point somewhere else entirely, which may not even be on this volume
+            file_details_dialog(s, row.inode);
+            return;
         }
 
         explore_a_volume_screen(s);
     });
 
-    let mut title = format!("Explore: {}:/", u.drive_letter.to_uppercase());
+    let mut title = format!("Explore: {}/", u.volume_name);
     if let Some((_, tail)) = u.dir_stack.split_first() {
         for inode in tail {
-            title.push_str(&*format!("{}/", index.0[*inode].as_ref().unwrap().name.as_ref().unwrap()));
+            // Writing to a String can't fail
+            let _ = write!(title, "{}/", index.name(*inode).unwrap_or("?"));
         }
     }
+    if !u.has_volume_data {
+        title.push_str(" [$MFT only]");
+    }
+    if u.loaded_from_cache {
+        title.push_str(" [cached]");
+    }
+
+    let totals = index.totals(*parent_inode);
+    let mut layout = LinearLayout::vertical().child(table.with_name("table").full_screen());
+    layout.add_child(TextView::new(format!(
+        "{} files, {} folders: {} bytes, {} bytes on disk",
+        totals.file_count, totals.dir_count, totals.size, totals.allocated_size
+    )));
+    layout.add_child(TextView::new(format!(
+        "Index of {} records uses {} MB ({} MB before compacting).",
+        index.len().to_formatted_string(&Locale::en),
+        (u.index_memory_usage >> 20).to_formatted_string(&Locale::en),
+        (u.tree_memory_usage >> 20).to_formatted_string(&Locale::en)
+    )));
+    layout.add_child(TextView::new(if u.show_hidden {
+        "Press h to hide hidden files."
+    } else {
+        "Press h to show hidden files."
+    }));
+    if !u.tree_report.is_empty() {
+        layout.add_child(TextView::new(format!(
+            "{} problems were found in the directory structure, affected files are in /{}. Press r to view them.",
+            u.tree_report.anomalies.len(), ORPHANS_DIRECTORY_NAME
+        )));
+    }
+    if !u.has_volume_data {
+        layout.add_child(TextView::new(
+            "Loaded from a $MFT dump: file contents are not available, so hashing and content preview are disabled."
+        ));
+    }
 
     s.pop_layer();
     s.add_layer(
-        Dialog::around(table.with_name("table").full_screen())
-            .title(title)
+        OnEventView::new(
+            Dialog::around(layout)
+                .title(title)
+        ).on_event('h', |s| {
+            let u = get_user_data(s);
+            u.show_hidden = !u.show_hidden;
+            explore_a_volume_screen(s);
+        }).on_event('r', tree_report_dialog)
     )
-}
\ No newline at end of file
+}
+
+// Shows every path a file is linked at, its reparse point and its streams
+fn file_details_dialog(s: &mut Cursive, inode: usize) {
+    let index = get_user_data(s).index.as_ref().unwrap();
+    let f = index.file(inode).unwrap();
+
+    let mut text = String::from("Paths:");
+    for path in index.paths(f.index as usize) {
+        text.push_str(&format!("\n{}", path));
+    }
+
+    if let Some(reparse_point) = &f.reparse_point {
+        text.push_str(&format!("\n\n{}", reparse_point.description()));
+    }
+
+    text.push_str(&format!("\n\nStreams:\n(unnamed): {} bytes, {} allocated", f.file_size, f.allocated_size));
+    for stream in &f.streams {
+        text.push_str(&format!("\n{}: {} bytes, {} allocated", stream.name, stream.size, stream.allocated_size));
+    }
+
+    s.add_layer(
+        Dialog::around(ScrollView::new(TextView::new(text)))
+            .title(f.name().unwrap_or("<no name>"))
+            .button("Close", |s| {
+                s.pop_layer();
+            })
+    );
+}
+
+fn tree_report_dialog(s: &mut Cursive) {
+    let u = get_user_data(s);
+    if u.tree_report.is_empty() {
+        return;
+    }
+
+    let index = u.index.as_ref().unwrap();
+    let mut text = String::new();
+    for anomaly in &u.tree_report.anomalies {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&anomaly.description());
+        // Where the file can be found now
+        if let Some(path) = index.paths(anomaly.index()).first() {
+            text.push_str(&format!("\n    {}", path));
+        }
+    }
+
+    s.add_layer(
+        Dialog::around(ScrollView::new(TextView::new(text)))
+            .title("Directory Structure Problems")
+            .button("Close", |s| {
+                s.pop_layer();
+            })
+    );
+}
@@ -0,0 +1,165 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::{bail, Result};
+use crate::bytes::{le_u16, le_u64};
+
+pub const BOOT_SECTOR_SIZE: usize = 512;
+pub const MAX_SECTOR_SIZE: usize = 4096;
+
+// Volume geometry as recorded by NTFS itself in the first sector of the volume. Unlike the disk
+// geometry IOCTLs, this is right for dynamic disks, storage spaces, 4Kn devices and images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootSector {
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    // Excludes the backup boot sector at the very end of the volume
+    pub total_sectors: u64,
+    pub mft_lcn: u64,
+    pub mft_mirror_lcn: u64,
+    pub file_record_size: u32,
+    pub index_record_size: u32,
+    pub serial_number: u64,
+}
+
+impl BootSector {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < BOOT_SECTOR_SIZE {
+            bail!("Boot sector is truncated");
+        }
+        if &buf[3..11] != b"NTFS    " {
+            bail!("Not an NTFS boot sector: system ID is not \"NTFS    \"");
+        }
+        if le_u16(buf, 510) != 0xAA55 {
+            bail!("NTFS boot sector is missing its 0xAA55 signature");
+        }
+
+        let bytes_per_sector = le_u16(buf, 0x0B) as u32;
+        if !bytes_per_sector.is_power_of_two() || !(256..=MAX_SECTOR_SIZE as u32).contains(&bytes_per_sector) {
+            bail!("NTFS boot sector has invalid bytes per sector: {}", bytes_per_sector);
+        }
+
+        // Values above 0x80 are negative powers of two, used for clusters larger than 64 KB
+        let sectors_per_cluster = match buf[0x0D] {
+            v @ 1..=0x80 if v.is_power_of_two() => v as u32,
+            v @ 0xF0..=0xFF => 1u32 << (256 - v as u32),
+            v => bail!("NTFS boot sector has invalid sectors per cluster: 0x{:02X}", v),
+        };
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+
+        let file_record_size = record_size(buf[0x40] as i8, cluster_size)?;
+        let index_record_size = record_size(buf[0x44] as i8, cluster_size)?;
+
+        Ok(BootSector {
+            bytes_per_sector,
+            sectors_per_cluster,
+            total_sectors: le_u64(buf, 0x28),
+            mft_lcn: le_u64(buf, 0x30),
+            mft_mirror_lcn: le_u64(buf, 0x38),
+            file_record_size,
+            index_record_size,
+            serial_number: le_u64(buf, 0x48),
+        })
+    }
+
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut buf = [0u8; BOOT_SECTOR_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut buf)?;
+
+        Self::parse(&buf)
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.bytes_per_sector as u64 * self.sectors_per_cluster as u64
+    }
+
+    pub fn volume_size(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector as u64
+    }
+
+    pub fn mft_offset(&self) -> u64 {
+        self.mft_lcn * self.cluster_size()
+    }
+
+    pub fn mft_mirror_offset(&self) -> u64 {
+        self.mft_mirror_lcn * self.cluster_size()
+    }
+}
+
+// Record sizes are stored as clusters per record, or as a negative power of two in bytes when records are
+// smaller than a cluster (the usual case: -10 for 1 KB file records)
+fn record_size(value: i8, cluster_size: u32) -> Result<u32> {
+    let size = match value {
+        1.. => value as u64 * cluster_size as u64,
+        -31..=-1 => 1u64 << -value,
+        _ => bail!("NTFS boot sector has invalid record size: {}", value),
+    };
+
+    if !(256..=65536).contains(&size) {
+        bail!("NTFS boot sector has invalid record size: {} bytes", size);
+    }
+
+    Ok(size as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn boot_sector(bytes_per_sector: u16, sectors_per_cluster: u8, file_record: i8, index_record: i8) -> Vec<u8> {
+        let mut buf = vec![0u8; BOOT_SECTOR_SIZE];
+        buf[3..11].copy_from_slice(b"NTFS    ");
+        buf[0x0B..0x0D].copy_from_slice(&bytes_per_sector.to_le_bytes());
+        buf[0x0D] = sectors_per_cluster;
+        buf[0x28..0x30].copy_from_slice(&2_000_000u64.to_le_bytes());
+        buf[0x30..0x38].copy_from_slice(&786_432u64.to_le_bytes());
+        buf[0x38..0x40].copy_from_slice(&2u64.to_le_bytes());
+        buf[0x40] = file_record as u8;
+        buf[0x44] = index_record as u8;
+        buf[0x48..0x50].copy_from_slice(&0x1234_5678_9ABC_DEF0u64.to_le_bytes());
+        buf[510..512].copy_from_slice(&0xAA55u16.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn usual_geometry() {
+        let boot = BootSector::read(&mut Cursor::new(boot_sector(512, 8, -10, 1))).unwrap();
+        assert_eq!(boot.cluster_size(), 4096);
+        assert_eq!(boot.file_record_size, 1024);
+        assert_eq!(boot.index_record_size, 4096);
+        assert_eq!(boot.volume_size(), 2_000_000 * 512);
+        assert_eq!(boot.mft_offset(), 786_432 * 4096);
+        assert_eq!(boot.mft_mirror_offset(), 2 * 4096);
+        assert_eq!(boot.serial_number, 0x1234_5678_9ABC_DEF0);
+    }
+
+    #[test]
+    fn large_sectors_and_clusters() {
+        // 4Kn sectors, and 2 MB clusters stored as a negative power of two
+        let boot = BootSector::parse(&boot_sector(4096, 0xF7, -12, -12)).unwrap();
+        assert_eq!(boot.sectors_per_cluster, 512);
+        assert_eq!(boot.cluster_size(), 2 * 1024 * 1024);
+        assert_eq!(boot.file_record_size, 4096);
+    }
+
+    #[test]
+    fn invalid_boot_sectors() {
+        let mut not_ntfs = boot_sector(512, 8, -10, 1);
+        not_ntfs[3..11].copy_from_slice(b"EXFAT   ");
+        let mut no_signature = boot_sector(512, 8, -10, 1);
+        no_signature[510] = 0;
+
+        for buf in [
+            not_ntfs,
+            no_signature,
+            boot_sector(1000, 8, -10, 1),
+            boot_sector(512, 3, -10, 1),
+            boot_sector(512, 8, 0, 1),
+            boot_sector(512, 8, -7, 1),
+            boot_sector(512, 8, -10, 100),
+            boot_sector(512, 8, -10, 1)[..256].to_vec(),
+        ] {
+            assert!(BootSector::parse(&buf).is_err());
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use crate::bytes::{le_u16, le_u32, le_u64};
+use crate::mft::MftLayout;
+use crate::record::{FileAttributes, FileReference};
+use crate::reparse::{ReparseKind, ReparsePoint};
+use crate::time::{FileTime, Timestamps};
+use crate::usn::JournalPosition;
+use crate::volume::VolumeSource;
+use crate::{DataStream, FileLink, FileMetadata, VolumeIndexFlatArray};
+
+const CACHE_MAGIC: &[u8; 8] = b"WDINDEX\0";
+// Bump whenever the layout of the cache or of `FileMetadata` changes, so old caches are ignored
+const CACHE_VERSION: u32 = 3;
+
+// Which volume a cached index is of. It says nothing about whether the index is still up to date: $LogFile's
+// restart area only moves at checkpoints, so there's nothing cheap to read that changes with every change to the
+// volume. The USN journal position stored with the index is what brings it up to date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexCacheKey {
+    pub serial_number: u64,
+    pub mft_lcn: u64,
+    pub record_size: u32,
+}
+
+impl IndexCacheKey {
+    pub fn read<R: VolumeSource>(reader: &mut R) -> Result<Self> {
+        let layout = MftLayout::read(reader)?;
+
+        Ok(IndexCacheKey {
+            serial_number: layout.boot_sector.serial_number,
+            mft_lcn: layout.boot_sector.mft_lcn,
+            record_size: layout.record_size,
+        })
+    }
+
+    // One cache per volume, so a rescan replaces the stale one
+    pub fn path_in(&self, directory: &Path) -> PathBuf {
+        directory.join(format!("{:016X}.index", self.serial_number))
+    }
+
+    fn write(&self, w: &mut CacheWriter) -> Result<()> {
+        w.u64(self.serial_number)?;
+        w.u64(self.mft_lcn)?;
+        w.u32(self.record_size)
+    }
+
+    fn read_cached(r: &mut CacheReader) -> Result<Self> {
+        Ok(IndexCacheKey {
+            serial_number: r.u64()?,
+            mft_lcn: r.u64()?,
+            record_size: r.u32()?,
+        })
+    }
+}
+
+// LOCALAPPDATA on Windows, XDG_CACHE_HOME or ~/.cache elsewhere, and the temp directory when none are set
+fn cache_directory() -> PathBuf {
+    env::var_os("LOCALAPPDATA")
+        .or_else(|| env::var_os("XDG_CACHE_HOME"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("WinDedupe")
+}
+
+// A cached index, as it was when the USN journal was at `journal`. Only bringing it up to date from the journal
+// tells whether the volume has changed since.
+pub struct CachedIndex {
+    pub journal: JournalPosition,
+    pub index: VolumeIndexFlatArray,
+}
+
+impl VolumeIndexFlatArray {
+    pub fn load_cache(key: &IndexCacheKey) -> Result<Option<CachedIndex>> {
+        Self::load_from(&cache_directory(), key)
+    }
+
+    // None when there's no cache for the volume, or it is from an older version or of a reformatted volume
+    pub fn load_from(directory: &Path, key: &IndexCacheKey) -> Result<Option<CachedIndex>> {
+        let buf = match fs::read(key.path_in(directory)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut r = CacheReader { buf: &buf, position: 0 };
+        if r.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC || r.u32()? != CACHE_VERSION {
+            return Ok(None);
+        }
+        if IndexCacheKey::read_cached(&mut r)? != *key {
+            return Ok(None);
+        }
+        let journal = JournalPosition {
+            journal_id: r.u64()?,
+            next_usn: r.u64()?,
+        };
+
+        // Every record takes at least a byte, which keeps a corrupt count from allocating too much
+        let count = r.u64()? as usize;
+        if count > r.buf.len() - r.position {
+            bail!("Index cache is truncated");
+        }
+        let mut records = Vec::with_capacity(count);
+        for index in 0..count {
+            records.push(if r.u8()? != 0 { Some(read_file(&mut r, index)?) } else { None });
+        }
+
+        Ok(Some(CachedIndex {
+            journal,
+            index: VolumeIndexFlatArray(records),
+        }))
+    }
+
+    pub fn save_cache(&self, key: &IndexCacheKey, journal: JournalPosition) -> Result<()> {
+        self.save_to(&cache_directory(), key, journal)
+    }
+
+    // Written to a temporary file first, so an interrupted write never leaves a truncated cache behind
+    pub fn save_to(&self, directory: &Path, key: &IndexCacheKey, journal: JournalPosition) -> Result<()> {
+        fs::create_dir_all(directory)?;
+        let path = key.path_in(directory);
+        let temp_path = path.with_extension("tmp");
+
+        let mut w = CacheWriter(BufWriter::new(File::create(&temp_path)?));
+        w.bytes(CACHE_MAGIC)?;
+        w.u32(CACHE_VERSION)?;
+        key.write(&mut w)?;
+        w.u64(journal.journal_id)?;
+        w.u64(journal.next_usn)?;
+        w.u64(self.0.len() as u64)?;
+        for f in &self.0 {
+            match f {
+                Some(f) => {
+                    w.u8(1)?;
+                    write_file(&mut w, f)?;
+                }
+                None => w.u8(0)?,
+            }
+        }
+        w.0.flush()?;
+        drop(w);
+
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+// Children and totals aren't stored, as `build_tree` fills them in again
+fn write_file(w: &mut CacheWriter, f: &FileMetadata) -> Result<()> {
+    w.u16(f.sequence_number)?;
+    w.u8(f.is_dir as u8)?;
+    w.u32(f.attributes.0)?;
+    w.u64(f.file_size)?;
+    w.u64(f.allocated_size)?;
+    w.timestamps(&f.standard_information_times)?;
+    w.timestamps(&f.file_name_times)?;
+
+    w.u32(f.links.len() as u32)?;
+    for link in &f.links {
+        w.u64(link.parent.to_u64())?;
+        w.u8(link.namespace)?;
+        w.str(&link.name)?;
+    }
+
+    w.u32(f.streams.len() as u32)?;
+    for stream in &f.streams {
+        w.str(&stream.name)?;
+        w.u64(stream.size)?;
+        w.u64(stream.allocated_size)?;
+    }
+
+    match &f.reparse_point {
+        Some(r) => {
+            w.u8(1)?;
+            w.u32(r.tag)?;
+            w.u8(r.kind as u8)?;
+            w.u8(r.relative as u8)?;
+            match &r.target {
+                Some(target) => {
+                    w.u8(1)?;
+                    w.str(target)?;
+                }
+                None => w.u8(0)?,
+            }
+        }
+        None => w.u8(0)?,
+    }
+
+    Ok(())
+}
+
+fn read_file(r: &mut CacheReader, index: usize) -> Result<FileMetadata> {
+    let mut f = FileMetadata {
+        index: index as u64,
+        sequence_number: r.u16()?,
+        is_dir: r.u8()? != 0,
+        attributes: FileAttributes(r.u32()?),
+        file_size: r.u64()?,
+        allocated_size: r.u64()?,
+        standard_information_times: r.timestamps()?,
+        file_name_times: r.timestamps()?,
+        ..Default::default()
+    };
+
+    for _ in 0..r.u32()? {
+        f.links.push(FileLink {
+            parent: FileReference::from_u64(r.u64()?),
+            namespace: r.u8()?,
+            name: r.str()?,
+        });
+    }
+
+    for _ in 0..r.u32()? {
+        f.streams.push(DataStream {
+            name: r.str()?,
+            size: r.u64()?,
+            allocated_size: r.u64()?,
+        });
+    }
+
+    if r.u8()? != 0 {
+        let tag = r.u32()?;
+        let kind = match r.u8()? {
+            k if k == ReparseKind::Symlink as u8 => ReparseKind::Symlink,
+            k if k == ReparseKind::MountPoint as u8 => ReparseKind::MountPoint,
+            k if k == ReparseKind::Cloud as u8 => ReparseKind::Cloud,
+            k if k == ReparseKind::Dedup as u8 => ReparseKind::Dedup,
+            k if k == ReparseKind::Wof as u8 => ReparseKind::Wof,
+            _ => ReparseKind::Other,
+        };
+        let relative = r.u8()? != 0;
+        let target = if r.u8()? != 0 { Some(r.str()?) } else { None };
+        f.reparse_point = Some(ReparsePoint { tag, kind, target, relative });
+    }
+
+    Ok(f)
+}
+
+struct CacheWriter(BufWriter<File>);
+
+impl CacheWriter {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(self.0.write_all(bytes)?)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<()> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn str(&mut self, value: &str) -> Result<()> {
+        self.u32(value.len() as u32)?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn timestamps(&mut self, times: &Timestamps) -> Result<()> {
+        for time in [times.created, times.modified, times.mft_modified, times.accessed] {
+            self.u64(time.0)?;
+        }
+        Ok(())
+    }
+}
+
+struct CacheReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.position < len {
+            bail!("Index cache is truncated");
+        }
+        let bytes = &self.buf[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(le_u16(self.bytes(2)?, 0))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(le_u32(self.bytes(4)?, 0))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(le_u64(self.bytes(8)?, 0))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn timestamps(&mut self) -> Result<Timestamps> {
+        Ok(Timestamps {
+            created: FileTime(self.u64()?),
+            modified: FileTime(self.u64()?),
+            mft_modified: FileTime(self.u64()?),
+            accessed: FileTime(self.u64()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::testing::*;
+
+    // Each test uses a directory of its own
+    fn cache_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("win_dedupe_{}_cache_{}", std::process::id(), name))
+    }
+
+    const KEY: IndexCacheKey = IndexCacheKey {
+        serial_number: 0xDEAD_BEEF,
+        mft_lcn: 20,
+        record_size: 1024,
+    };
+
+    fn scan(volume: TestVolume) -> VolumeIndexFlatArray {
+        VolumeIndexFlatArray::from_volume_reader(&mut Cursor::new(volume.finish()), None).unwrap()
+    }
+
+    fn files(index: &VolumeIndexFlatArray) -> Vec<Option<String>> {
+        index.0.iter().map(|f| f.as_ref().map(describe)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = cache_dir("round_trip");
+        let index = scan(varied_volume());
+        let journal = JournalPosition { journal_id: 7, next_usn: 4096 };
+        index.save_to(&dir, &KEY, journal).unwrap();
+
+        let cached = VolumeIndexFlatArray::load_from(&dir, &KEY).unwrap().unwrap();
+        assert_eq!(cached.journal, journal);
+        assert_eq!(files(&cached.index), files(&index));
+
+        // Saving again replaces the cache
+        let journal = JournalPosition { next_usn: 8192, ..journal };
+        scan(basic_volume()).save_to(&dir, &KEY, journal).unwrap();
+        let cached = VolumeIndexFlatArray::load_from(&dir, &KEY).unwrap().unwrap();
+        assert_eq!(cached.journal, journal);
+        assert_eq!(files(&cached.index), files(&scan(basic_volume())));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unusable_caches() {
+        let dir = cache_dir("unusable");
+        assert!(VolumeIndexFlatArray::load_from(&dir, &KEY).unwrap().is_none());
+
+        let journal = JournalPosition { journal_id: 7, next_usn: 0 };
+        scan(basic_volume()).save_to(&dir, &KEY, journal).unwrap();
+        // The volume has been reformatted, with its MFT somewhere else
+        assert!(VolumeIndexFlatArray::load_from(&dir, &IndexCacheKey { mft_lcn: 4, ..KEY }).unwrap().is_none());
+
+        let path = KEY.path_in(&dir);
+        let cache = fs::read(&path).unwrap();
+        fs::write(&path, &cache[..cache.len() - 1]).unwrap();
+        assert!(VolumeIndexFlatArray::load_from(&dir, &KEY).is_err());
+
+        let mut older_version = cache.clone();
+        older_version[8..12].copy_from_slice(&(CACHE_VERSION - 1).to_le_bytes());
+        fs::write(&path, &older_version).unwrap();
+        assert!(VolumeIndexFlatArray::load_from(&dir, &KEY).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn key_from_the_volume() {
+        assert_eq!(IndexCacheKey::read(&mut Cursor::new(basic_volume().finish())).unwrap(), KEY);
+    }
+}
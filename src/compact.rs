@@ -0,0 +1,368 @@
+use std::mem::size_of;
+use ntfs::KnownNtfsFileRecordNumber::RootDirectory;
+use crate::record::{FileAttributes, FileReference};
+use crate::reparse::ReparsePoint;
+use crate::time::Timestamps;
+use crate::{ChildLink, DataStream, DirectoryTotals, FileLink, FileMetadata, VolumeIndexTree, MAX_PATH_DEPTH};
+
+const FLAG_IN_USE: u8 = 0x01;
+const FLAG_DIRECTORY: u8 = 0x02;
+
+// `VolumeIndexTree` stored as one array per field, so a volume with millions of files costs a few dozen bytes per
+// record rather than several allocations each. Links and children are stored CSR style: record `i` owns
+// `link_starts[i]..link_starts[i + 1]` of the per-link arrays, and likewise for children. Every name lives in one
+// string. Fields few files have, like reparse points and named streams, are kept in side tables sorted by record.
+#[derive(Default)]
+pub struct CompactVolumeIndex {
+    // Per record
+    flags: Vec<u8>,
+    sequence_numbers: Vec<u16>,
+    attributes: Vec<FileAttributes>,
+    file_sizes: Vec<u64>,
+    allocated_sizes: Vec<u64>,
+    standard_information_times: Vec<Timestamps>,
+    // Of the first link
+    file_name_times: Vec<Timestamps>,
+    link_starts: Vec<u32>,
+    child_starts: Vec<u32>,
+
+    // Per link
+    link_parents: Vec<u64>,
+    link_namespaces: Vec<u8>,
+    // Into `names`, with one more entry than there are links
+    name_starts: Vec<u32>,
+    names: String,
+
+    // Per child
+    child_indices: Vec<u32>,
+    child_links: Vec<u16>,
+
+    // Side tables, sorted by record
+    totals: Vec<(u32, DirectoryTotals)>,
+    reparse_points: Vec<(u32, ReparsePoint)>,
+    streams: Vec<(u32, DataStream)>,
+}
+
+impl CompactVolumeIndex {
+    // Consumes the tree from the end, shrinking it as it goes, so the tree's allocations are freed as the compact
+    // copy fills in. Where everything goes is worked out first, so the arrays are allocated once at their final size.
+    pub fn from_tree(mut tree: VolumeIndexTree) -> Self {
+        let count = tree.0.len();
+        let mut index = CompactVolumeIndex {
+            flags: vec![0; count],
+            sequence_numbers: vec![0; count],
+            attributes: vec![FileAttributes::default(); count],
+            file_sizes: vec![0; count],
+            allocated_sizes: vec![0; count],
+            standard_information_times: vec![Timestamps::default(); count],
+            file_name_times: vec![Timestamps::default(); count],
+            link_starts: Vec::with_capacity(count + 1),
+            child_starts: Vec::with_capacity(count + 1),
+            name_starts: vec![0],
+            ..Default::default()
+        };
+
+        index.link_starts.push(0);
+        index.child_starts.push(0);
+        for f in &tree.0 {
+            let (links, children) = f.as_ref().map_or((&[][..], &[][..]), |f| (&f.links[..], &f.children[..]));
+            let mut name_end = *index.name_starts.last().unwrap();
+            index.name_starts.extend(links.iter().map(|l| {
+                name_end += l.name.len() as u32;
+                name_end
+            }));
+            index.link_starts.push(index.link_starts.last().unwrap() + links.len() as u32);
+            index.child_starts.push(index.child_starts.last().unwrap() + children.len() as u32);
+        }
+        index.name_starts.shrink_to_fit();
+        let link_count = index.name_starts.len() - 1;
+        let child_count = *index.child_starts.last().unwrap() as usize;
+        index.link_parents = vec![0; link_count];
+        index.link_namespaces = vec![0; link_count];
+        index.child_indices = vec![0; child_count];
+        index.child_links = vec![0; child_count];
+        let mut names = vec![0u8; *index.name_starts.last().unwrap() as usize];
+
+        while let Some(f) = tree.0.pop() {
+            let i = tree.0.len();
+            // Halving the capacity each time copies no more than the tree's array once in all
+            if tree.0.len() < tree.0.capacity() / 2 {
+                tree.0.shrink_to_fit();
+            }
+            let f = match f {
+                Some(f) => f,
+                None => continue,
+            };
+
+            index.flags[i] = FLAG_IN_USE | if f.is_dir { FLAG_DIRECTORY } else { 0 };
+            index.sequence_numbers[i] = f.sequence_number;
+            index.attributes[i] = f.attributes;
+            index.file_sizes[i] = f.file_size;
+            index.allocated_sizes[i] = f.allocated_size;
+            index.standard_information_times[i] = f.standard_information_times;
+            index.file_name_times[i] = f.file_name_times;
+
+            for (link, l) in f.links.iter().zip(index.link_starts[i] as usize..) {
+                index.link_parents[l] = link.parent.to_u64();
+                index.link_namespaces[l] = link.namespace;
+                names[index.name_starts[l] as usize..index.name_starts[l + 1] as usize].copy_from_slice(link.name.as_bytes());
+            }
+
+            for (child, c) in f.children.iter().zip(index.child_starts[i] as usize..) {
+                index.child_indices[c] = child.index as u32;
+                index.child_links[c] = child.link as u16;
+            }
+
+            // Side tables are built backwards and reversed at the end
+            if f.is_dir {
+                index.totals.push((i as u32, f.totals));
+            }
+            if let Some(reparse_point) = f.reparse_point {
+                index.reparse_points.push((i as u32, reparse_point));
+            }
+            index.streams.extend(f.streams.into_iter().rev().map(|s| (i as u32, s)));
+        }
+
+        // Only whole names were copied in, so this can't fail
+        index.names = String::from_utf8(names).unwrap();
+        index.totals.reverse();
+        index.totals.shrink_to_fit();
+        index.reparse_points.reverse();
+        index.reparse_points.shrink_to_fit();
+        index.streams.reverse();
+        index.streams.shrink_to_fit();
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    pub fn is_in_use(&self, inode: usize) -> bool {
+        self.flags.get(inode).is_some_and(|f| f & FLAG_IN_USE != 0)
+    }
+
+    pub fn is_dir(&self, inode: usize) -> bool {
+        self.flags[inode] & FLAG_DIRECTORY != 0
+    }
+
+    pub fn sequence_number(&self, inode: usize) -> u16 {
+        self.sequence_numbers[inode]
+    }
+
+    pub fn attributes(&self, inode: usize) -> FileAttributes {
+        self.attributes[inode]
+    }
+
+    pub fn file_size(&self, inode: usize) -> u64 {
+        self.file_sizes[inode]
+    }
+
+    pub fn allocated_size(&self, inode: usize) -> u64 {
+        self.allocated_sizes[inode]
+    }
+
+    pub fn standard_information_times(&self, inode: usize) -> Timestamps {
+        self.standard_information_times[inode]
+    }
+
+    pub fn file_name_times(&self, inode: usize) -> Timestamps {
+        self.file_name_times[inode]
+    }
+
+    pub fn link_count(&self, inode: usize) -> usize {
+        (self.link_starts[inode + 1] - self.link_starts[inode]) as usize
+    }
+
+    pub fn link_name(&self, inode: usize, link: usize) -> &str {
+        let l = self.link_starts[inode] as usize + link;
+        &self.names[self.name_starts[l] as usize..self.name_starts[l + 1] as usize]
+    }
+
+    pub fn link_parent(&self, inode: usize, link: usize) -> FileReference {
+        FileReference::from_u64(self.link_parents[self.link_starts[inode] as usize + link])
+    }
+
+    // The name of the first link, like `FileMetadata::name`
+    pub fn name(&self, inode: usize) -> Option<&str> {
+        if self.is_in_use(inode) && self.link_count(inode) > 0 {
+            Some(self.link_name(inode, 0))
+        } else {
+            None
+        }
+    }
+
+    pub fn totals(&self, inode: usize) -> DirectoryTotals {
+        side_table(&self.totals, inode).first().map(|(_, t)| *t).unwrap_or_default()
+    }
+
+    pub fn reparse_point(&self, inode: usize) -> Option<&ReparsePoint> {
+        side_table(&self.reparse_points, inode).first().map(|(_, r)| r)
+    }
+
+    pub fn streams(&self, inode: usize) -> impl Iterator<Item = &DataStream> {
+        side_table(&self.streams, inode).iter().map(|(_, s)| s)
+    }
+
+    pub fn is_placeholder(&self, inode: usize) -> bool {
+        self.reparse_point(inode).is_some_and(|r| r.is_placeholder()) || self.attributes(inode).is_recall_on_access()
+    }
+
+    pub fn dir_children(&self, inode: usize) -> Option<impl Iterator<Item = ChildLink> + '_> {
+        if !self.is_in_use(inode) {
+            return None;
+        }
+
+        // Files with inode > 24 are ordinary files/directories
+        Some(self.children(inode).filter(|c| c.index > 24))
+    }
+
+    // Including system files, unlike `dir_children`
+    fn children(&self, inode: usize) -> impl Iterator<Item = ChildLink> + '_ {
+        let range = self.child_starts[inode] as usize..self.child_starts[inode + 1] as usize;
+        range.map(|c| ChildLink {
+            index: self.child_indices[c] as usize,
+            link: self.child_links[c] as usize,
+        })
+    }
+
+    // Every full path a file is reachable by, one per hard link
+    pub fn paths(&self, inode: usize) -> Vec<String> {
+        if !self.is_in_use(inode) {
+            return Vec::new();
+        }
+
+        (0..self.link_count(inode)).map(|link| {
+            let mut components = vec![self.link_name(inode, link)];
+            let mut parent = self.link_parent(inode, link).entry as usize;
+            // Bounded in case of a directory loop, which only corruption can cause
+            for _ in 0..MAX_PATH_DEPTH {
+                if parent == RootDirectory as usize {
+                    break;
+                }
+                match self.name(parent) {
+                    Some(name) => {
+                        components.push(name);
+                        parent = self.link_parent(parent, 0).entry as usize;
+                    }
+                    None => {
+                        components.push("<unknown>");
+                        break;
+                    }
+                }
+            }
+
+            components.reverse();
+            format!("/{}", components.join("/"))
+        }).collect()
+    }
+
+    // Everything known about one file, for when a view needs all of it
+    pub fn file(&self, inode: usize) -> Option<FileMetadata> {
+        if !self.is_in_use(inode) {
+            return None;
+        }
+
+        Some(FileMetadata {
+            index: inode as u64,
+            sequence_number: self.sequence_number(inode),
+            links: (0..self.link_count(inode)).map(|link| FileLink {
+                parent: self.link_parent(inode, link),
+                name: String::from(self.link_name(inode, link)),
+                namespace: self.link_namespaces[self.link_starts[inode] as usize + link],
+            }).collect(),
+            is_dir: self.is_dir(inode),
+            attributes: self.attributes(inode),
+            reparse_point: self.reparse_point(inode).cloned(),
+            file_size: self.file_size(inode),
+            allocated_size: self.allocated_size(inode),
+            streams: self.streams(inode).cloned().collect(),
+            standard_information_times: self.standard_information_times(inode),
+            file_name_times: self.file_name_times(inode),
+            children: self.children(inode).collect(),
+            totals: self.totals(inode),
+        })
+    }
+
+    // Bytes allocated for the index, not counting allocator overhead
+    pub fn memory_usage(&self) -> usize {
+        vec_bytes(&self.flags)
+            + vec_bytes(&self.sequence_numbers)
+            + vec_bytes(&self.attributes)
+            + vec_bytes(&self.file_sizes)
+            + vec_bytes(&self.allocated_sizes)
+            + vec_bytes(&self.standard_information_times)
+            + vec_bytes(&self.file_name_times)
+            + vec_bytes(&self.link_starts)
+            + vec_bytes(&self.child_starts)
+            + vec_bytes(&self.link_parents)
+            + vec_bytes(&self.link_namespaces)
+            + vec_bytes(&self.name_starts)
+            + self.names.capacity()
+            + vec_bytes(&self.child_indices)
+            + vec_bytes(&self.child_links)
+            + vec_bytes(&self.totals)
+            + vec_bytes(&self.reparse_points)
+            + self.reparse_points.iter().map(|(_, r)| r.target.as_ref().map_or(0, |t| t.capacity())).sum::<usize>()
+            + vec_bytes(&self.streams)
+            + self.streams.iter().map(|(_, s)| s.name.capacity()).sum::<usize>()
+    }
+}
+
+impl VolumeIndexTree {
+    // Bytes allocated for the tree, not counting allocator overhead, to compare against `CompactVolumeIndex`
+    pub fn memory_usage(&self) -> usize {
+        vec_bytes(&self.0) + self.0.iter().flatten().map(|f| {
+            vec_bytes(&f.links)
+                + f.links.iter().map(|l| l.name.capacity()).sum::<usize>()
+                + f.reparse_point.as_ref().map_or(0, |r| r.target.as_ref().map_or(0, |t| t.capacity()))
+                + vec_bytes(&f.streams)
+                + f.streams.iter().map(|s| s.name.capacity()).sum::<usize>()
+                + vec_bytes(&f.children)
+        }).sum::<usize>()
+    }
+}
+
+fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+// The entries of a side table belonging to one record
+fn side_table<T>(table: &[(u32, T)], inode: usize) -> &[(u32, T)] {
+    let start = table.partition_point(|(i, _)| (*i as usize) < inode);
+    let end = table.partition_point(|(i, _)| (*i as usize) <= inode);
+    &table[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::testing::*;
+    use crate::VolumeIndexFlatArray;
+
+    #[test]
+    fn same_as_the_tree() {
+        let mut volume = Cursor::new(varied_volume().finish());
+        let (tree, _) = VolumeIndexFlatArray::from_volume_reader(&mut volume, None).unwrap().build_tree();
+        let files: Vec<_> = tree.0.iter().map(|f| f.as_ref().map(describe)).collect();
+        let paths: Vec<_> = (0..tree.0.len()).map(|i| tree.paths(i)).collect();
+        assert_eq!(paths[26], ["/b.bin", "/docs/b again.bin"]);
+
+        let index = CompactVolumeIndex::from_tree(tree);
+        assert_eq!(index.len(), files.len());
+        for (i, file) in files.iter().enumerate() {
+            assert_eq!(&index.file(i).as_ref().map(describe), file);
+            assert_eq!(index.paths(i), paths[i]);
+        }
+        assert_eq!(index.name(26), Some("b.bin"));
+        assert_eq!(index.file_name_times(26).created.0, 21);
+        assert_eq!(index.streams(26).map(|s| s.name.as_str()).collect::<Vec<_>>(), ["Zone.Identifier"]);
+        assert_eq!(index.reparse_point(27).and_then(|r| r.target.as_deref()), Some(r"\??\C:\a"));
+        assert!(index.name(29).is_none() && index.dir_children(29).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use crate::boot::BootSector;
+use crate::volume::{SectorDevice, SectorReader};
+
+// Raw (`.img`/`.dd`) dumps don't record their sector size. Volume images take it from their boot sector,
+// and whole disk images fall back to 512.
+pub const DEFAULT_IMAGE_SECTOR_SIZE: u32 = 512;
+
+fn image_sector_size(file: &mut File) -> u32 {
+    BootSector::read(file).map_or(DEFAULT_IMAGE_SECTOR_SIZE, |b| b.bytes_per_sector)
+}
+
+// A raw volume image read through the same sector buffering as a live volume
+pub type ImageReader = SectorReader<ImageFile>;
+
+pub struct ImageFile {
+    pub file: File,
+    pub length: u64,
+    pub sector_size: u32,
+}
+
+impl ImageFile {
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let sector_size = image_sector_size(&mut file);
+
+        Ok(ImageFile {
+            file,
+            length,
+            sector_size,
+        })
+    }
+}
+
+impl SectorDevice for ImageFile {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        // Unlike ReadFile on a volume, File::read may stop short before the end of the file
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(filled)
+    }
+}
+
+impl ImageReader {
+    pub fn open_path(path: &str) -> Result<Self> {
+        Ok(SectorReader::new(ImageFile::open_path(path)?))
+    }
+}
+
+// A split raw image (`disk.001`, `disk.002`, ...) presented as one contiguous image
+pub type SegmentedImageReader = SectorReader<SegmentedImage>;
+
+pub struct ImageSegment {
+    pub file: File,
+    // Offset of the segment's first byte within the stitched image
+    pub start: u64,
+    pub length: u64,
+}
+
+pub struct SegmentedImage {
+    pub segments: Vec<ImageSegment>,
+    pub length: u64,
+    pub sector_size: u32,
+}
+
+impl SegmentedImage {
+    // Segments are stitched in the order given
+    pub fn open_paths<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut segments = Vec::with_capacity(paths.len());
+        let mut length = 0u64;
+
+        for path in paths {
+            let file = File::open(path)?;
+            let segment_length = file.metadata()?.len();
+            segments.push(ImageSegment {
+                file,
+                start: length,
+                length: segment_length,
+            });
+            length += segment_length;
+        }
+
+        if segments.is_empty() {
+            bail!("A segmented image needs at least one segment");
+        }
+        let sector_size = image_sector_size(&mut segments[0].file);
+
+        Ok(SegmentedImage {
+            segments,
+            length,
+            sector_size,
+        })
+    }
+
+    // Opens `path` and every consecutively numbered segment after it
+    pub fn open_first_segment(path: &str) -> Result<Self> {
+        Self::open_paths(&segment_paths(path)?)
+    }
+}
+
+// If `path` has a numeric extension (`.001`), returns it and every existing segment that follows it
+pub fn segment_paths(path: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(path);
+    let (stem, number) = match segment_number(path) {
+        Some(n) => n,
+        None => bail!("\"{}\" is not a numbered image segment", path.display()),
+    };
+    let width = path.extension().unwrap().len();
+
+    let mut paths = vec![path.to_path_buf()];
+    for n in number + 1.. {
+        let next = path.with_file_name(format!("{}.{:0width$}", stem, n, width = width));
+        if !next.is_file() {
+            break;
+        }
+        paths.push(next);
+    }
+
+    Ok(paths)
+}
+
+// Splits `disk.001` into ("disk", 1)
+pub fn segment_number(path: &Path) -> Option<(String, u64)> {
+    let extension = path.extension()?.to_str()?;
+    if extension.is_empty() || !extension.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((path.file_stem()?.to_str()?.to_string(), extension.parse().ok()?))
+}
+
+impl SectorDevice for SegmentedImage {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+
+        // Find the segment containing `offset`, then keep reading into the following segments
+        let mut i = self.segments.partition_point(|s| s.start + s.length <= offset);
+        while filled < buf.len() && i < self.segments.len() {
+            let segment = &mut self.segments[i];
+            let segment_offset = offset + filled as u64 - segment.start;
+            let n = (buf.len() - filled).min((segment.length - segment_offset) as usize);
+
+            segment.file.seek(SeekFrom::Start(segment_offset))?;
+            segment.file.read_exact(&mut buf[filled..filled + n])?;
+
+            filled += n;
+            i += 1;
+        }
+
+        Ok(filled)
+    }
+}
+
+impl SegmentedImageReader {
+    pub fn open_first_segment(path: &str) -> Result<Self> {
+        Ok(SectorReader::new(SegmentedImage::open_first_segment(path)?))
+    }
+
+    pub fn open_paths<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        Ok(SectorReader::new(SegmentedImage::open_paths(paths)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_numbers() {
+        assert_eq!(segment_number(Path::new("disk.001")), Some((String::from("disk"), 1)));
+        assert_eq!(segment_number(Path::new("/images/a.b.0042")), Some((String::from("a.b"), 42)));
+        assert_eq!(segment_number(Path::new("disk.img")), None);
+        assert_eq!(segment_number(Path::new("disk")), None);
+    }
+
+    #[test]
+    fn segmented_image() {
+        let dir = std::env::temp_dir().join(format!("win_dedupe_{}_segments", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        // Segments needn't be sector sized, and the run of segments ends at the first gap
+        for (n, range) in [(1, 0..1000), (2, 1000..2500), (3, 2500..3000), (5, 0..512)] {
+            std::fs::write(dir.join(format!("disk.{:03}", n)), &contents[range]).unwrap();
+        }
+
+        let paths = segment_paths(dir.join("disk.001").to_str().unwrap()).unwrap();
+        assert_eq!(paths.len(), 3);
+
+        let mut image = SegmentedImage::open_paths(&paths).unwrap();
+        assert_eq!(image.length(), 3000);
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(image.read_sectors(0, &mut buf).unwrap(), 3000);
+        assert_eq!(buf[..3000], contents[..]);
+        assert_eq!(image.read_sectors(512, &mut buf[..1024]).unwrap(), 1024);
+        assert_eq!(buf[..1024], contents[512..1536]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn volumes_from_image_files() {
+        let dir = std::env::temp_dir().join(format!("win_dedupe_{}_images", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = crate::testing::basic_volume().finish();
+        std::fs::write(dir.join("volume.img"), &image).unwrap();
+        std::fs::write(dir.join("volume.001"), &image[..100_000]).unwrap();
+        std::fs::write(dir.join("volume.002"), &image[100_000..]).unwrap();
+
+        for name in ["volume.img", "volume.001"] {
+            let mut volume = crate::open_volume(dir.join(name).to_str().unwrap()).unwrap();
+            assert_eq!((volume.length(), volume.sector_size()), (image.len() as u64, 512));
+
+            // Unaligned, and across the segments
+            let mut buf = vec![0u8; 1000];
+            volume.seek(SeekFrom::Start(99_500)).unwrap();
+            volume.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, image[99_500..100_500]);
+
+            let index = crate::VolumeIndexFlatArray::from_volume_reader(&mut volume, None).unwrap();
+            assert_eq!(index.0[31].as_ref().unwrap().name(), Some("a.txt"));
+        }
+
+        assert!(crate::open_volume(dir.join("missing.img").to_str().unwrap()).is_err());
+        // Drive letters are never taken for image files
+        #[cfg(not(windows))]
+        assert!(crate::open_volume("C:").is_err_and(|e| e.to_string().contains("only supported on Windows")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
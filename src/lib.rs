@@ -1,374 +1,729 @@
-use ntfs::Ntfs;
-use std::collections::{BTreeSet, VecDeque};
-use std::{
-    ffi::c_void,
-    io::{Read, Seek, SeekFrom},
-    mem::size_of,
-    *,
-};
-use std::collections::btree_set::Iter;
-use std::io::SeekFrom::Start;
-use std::iter::FilterMap;
-use anyhow::Result;
-use std::str::from_utf8;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::io::SeekFrom::{End, Start};
+use std::ops::AddAssign;
+use anyhow::{bail, Result};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use mft::attribute::{MftAttributeContent, MftAttributeType};
-use mft::attribute::header::ResidentialHeader::{NonResident, Resident};
-use mft::attribute::x30::FileNamespace::DOS;
-use mft::entry::EntryFlags;
-use mft::MftParser;
-use ntfs::KnownNtfsFileRecordNumber::{MFT, RootDirectory};
-use windows::core::PCWSTR;
-use windows::Win32::Foundation::{CloseHandle, GENERIC_READ, HANDLE};
-use windows::Win32::Storage::FileSystem::{CreateFileW, FILE_BEGIN, FILE_END, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE, FILE_TYPE_DISK, GetFileType, OPEN_EXISTING, ReadFile, SetFilePointerEx};
-use windows::Win32::System::IO::DeviceIoControl;
-use windows::Win32::System::Ioctl::{DISK_GEOMETRY, IOCTL_DISK_GET_DRIVE_GEOMETRY};
-
+use std::sync::atomic::AtomicUsize;
+use ntfs::KnownNtfsFileRecordNumber::RootDirectory;
+use crate::parse::{merge_records, parse_records};
+
+mod bytes;
+mod boot;
+mod record;
+mod mft;
+mod parse;
+mod compact;
+mod cache;
+mod usn;
+mod stream;
+mod lznt1;
+mod reparse;
+mod time;
+mod volume;
+mod image;
+mod vhd;
+mod partition;
+#[cfg(windows)]
+mod win32;
+#[cfg(test)]
+mod testing;
+
+pub use boot::*;
+pub use record::*;
+pub use mft::*;
+pub use compact::*;
+pub use cache::*;
+pub use usn::*;
+pub use stream::*;
+pub use reparse::*;
+pub use time::*;
+pub use volume::*;
+pub use image::*;
+pub use vhd::*;
+pub use partition::*;
+#[cfg(windows)]
+pub use win32::*;
 
 // An struct storing the bare minimum needed for this program to work
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct FileMetadata {
-    pub name: Option<String>,
     pub index: u64,
-    // Because hard links exist, a file can have multiple parent directories
-    pub parent_indices: BTreeSet<usize>,
+    // Of the MFT record, which references to this file must match
+    pub sequence_number: u16,
+    // Because hard links exist, a file can have multiple names, each in its own parent directory
+    pub links: Vec<FileLink>,
     pub is_dir: bool,
+    // From $STANDARD_INFORMATION
+    pub attributes: FileAttributes,
+    // Symlinks, junctions and placeholders
+    pub reparse_point: Option<ReparsePoint>,
+    // Of the unnamed $DATA stream, i.e. what Explorer calls the file's size
     pub file_size: u64,
+    // Clusters actually used on disk, so less than the file size for sparse and compressed files
     pub allocated_size: u64,
-    pub children_indices: BTreeSet<usize>,
-    pub children_size: u64,
+    // Alternate data streams, such as Zone.Identifier
+    pub streams: Vec<DataStream>,
+    pub standard_information_times: Timestamps,
+    // From the $FILE_NAME attribute of the first link
+    pub file_name_times: Timestamps,
+    pub children: Vec<ChildLink>,
+    // Of everything below a directory, filled in by `build_tree`
+    pub totals: DirectoryTotals,
 }
 
-// Win32 only handles disk IO that is sector aligned and operates on whole sectors
-pub struct VolumeReader {
-    pub handle: HANDLE,
-    pub virtual_file_ptr: i64,
-    pub read_buf_ptr: Option<i64>,
-    pub read_buf: Vec<u8>,
-    pub geometry: DISK_GEOMETRY,
+// One $FILE_NAME attribute, i.e. one hard link. DOS 8.3 names only duplicate a long name, so they're left out.
+#[derive(Clone, Debug)]
+pub struct FileLink {
+    pub parent: FileReference,
+    pub name: String,
+    pub namespace: u8,
 }
 
-impl VolumeReader {
-    pub fn from_raw_handle(handle: HANDLE) -> Result<Self> {
-        let mut geometry: DISK_GEOMETRY = Default::default();
-        unsafe {
-            assert_eq!(GetFileType(handle), FILE_TYPE_DISK);
-
-            DeviceIoControl(
-                handle,
-                IOCTL_DISK_GET_DRIVE_GEOMETRY,
-                None,
-                0,
-                Some(&mut geometry as *mut _ as *mut c_void),
-                size_of::<DISK_GEOMETRY>() as u32,
-                None,
-                None,
-            )?;
-        }
+// A directory entry: the child file and which of its links puts it in the directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChildLink {
+    pub index: usize,
+    pub link: usize,
+}
 
-        Ok(VolumeReader {
-            handle,
-            virtual_file_ptr: 0,
-            read_buf: vec![0u8; 2usize.pow(22)], // 4 MB buffer size
-            read_buf_ptr: None,
-            geometry,
-        })
+impl FileMetadata {
+    // The name of the first link, for when any name will do. Directories can't be hard linked, so this is their only name.
+    pub fn name(&self) -> Option<&str> {
+        self.links.first().map(|l| l.name.as_str())
     }
 
-    pub fn open_path(path: &str) -> Result<VolumeReader> {
-        let mut path: Vec<u16> = path.encode_utf16().collect();
-        path.push(0);
-
-        unsafe {
-            let disk_handle = CreateFileW(
-                PCWSTR::from_raw(path.as_ptr()),
-                GENERIC_READ.0,
-                FILE_SHARE_READ | FILE_SHARE_WRITE,
-                None,
-                OPEN_EXISTING,
-                FILE_FLAGS_AND_ATTRIBUTES(0),
-                None,
-            )?;
-
-            Ok(VolumeReader::from_raw_handle(disk_handle)?)
-        }
+    // Placeholders must never have their contents read, see `ReparsePoint::is_placeholder`
+    pub fn is_placeholder(&self) -> bool {
+        self.reparse_point.as_ref().is_some_and(|r| r.is_placeholder()) || self.attributes.is_recall_on_access()
     }
 }
 
-impl Read for VolumeReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        // println!("Read length: {}", buf.len());
+// Recursive totals of a directory. A file hard linked in several places below a directory only counts once there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirectoryTotals {
+    // Including named streams, which Explorer leaves out
+    pub size: u64,
+    pub allocated_size: u64,
+    pub file_count: u64,
+    pub dir_count: u64,
+}
 
-        // Invalidate buffer if new read exceeds its boundaries
-        if let Some(p) = self.read_buf_ptr {
-            if self.virtual_file_ptr < p {
-                self.read_buf_ptr = None;
-            }
-            if self.virtual_file_ptr + buf.len() as i64 >= p + self.read_buf.len() as i64 {
-                self.read_buf_ptr = None;
-            }
-        }
+impl DirectoryTotals {
+    fn add_file(&mut self, f: &FileMetadata) {
+        self.size += f.file_size + f.streams.iter().map(|s| s.size).sum::<u64>();
+        self.allocated_size += f.allocated_size + f.streams.iter().map(|s| s.allocated_size).sum::<u64>();
+        self.file_count += 1;
+    }
+}
 
-        match self.read_buf_ptr {
-            Some(_) => {}
-            None => unsafe {
-                let bps = self.geometry.BytesPerSector as i64;
-                // round down to lower sector
-                let read_start = self.virtual_file_ptr / bps * bps;
-                let read_len = self.read_buf.len();
-
-                // println!(
-                // "Buffered read: {} - {} [{}]",
-                // read_bytes.start, read_bytes.end, read_len,
-                // );
-
-                SetFilePointerEx(self.handle, read_start, None, FILE_BEGIN)?;
-                ReadFile(
-                    self.handle,
-                    Some(&mut self.read_buf[0..read_len]),
-                    None,
-                    None,
-                )?;
-
-                self.read_buf_ptr = Some(read_start);
-            },
-        }
+impl AddAssign for DirectoryTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.size += other.size;
+        self.allocated_size += other.allocated_size;
+        self.file_count += other.file_count;
+        self.dir_count += other.dir_count;
+    }
+}
 
-        let vec_offs = (self.virtual_file_ptr - self.read_buf_ptr.unwrap()) as usize;
-        buf.clone_from_slice(&self.read_buf[vec_offs..vec_offs + buf.len()]);
-        self.virtual_file_ptr += buf.len() as i64;
+// A named $DATA attribute
+#[derive(Clone, Debug)]
+pub struct DataStream {
+    pub name: String,
+    pub size: u64,
+    pub allocated_size: u64,
+}
 
-        Ok(buf.len())
+pub fn verify_ntfs_system_id<T: Read + Seek>(reader: &mut T) -> bool {
+    // Read 8 byte system ID, should be "NTFS    "
+    // Anything that can't be read (e.g. an empty partition) just isn't NTFS
+    let mut buf = [0u8; 8];
+    if reader.seek(Start(3)).is_err() || reader.read_exact(&mut buf).is_err() {
+        return false;
     }
+    &buf == b"NTFS    "
 }
 
-impl Seek for VolumeReader {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
-        let mut new_file_ptr = 0i64;
-        unsafe {
-            match pos {
-                SeekFrom::Start(offset) => {
-                    self.virtual_file_ptr = offset as i64;
-                }
-                SeekFrom::End(offset) => {
-                    SetFilePointerEx(self.handle, 0, Some(&mut new_file_ptr), FILE_END)?;
-                    self.virtual_file_ptr = new_file_ptr + offset;
-                }
-                SeekFrom::Current(offset) => {
-                    self.virtual_file_ptr += offset;
-                }
-            };
-        }
+// Where `build_tree` puts files it can't attach to their parent directory
+pub const ORPHANS_DIRECTORY_NAME: &str = "$Orphans";
+
+// Something wrong with the directory structure, found while building the tree. Every affected link ends up in
+// the orphans directory. `index` and `link` identify the file and which of its hard links is affected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeAnomaly {
+    // The parent record is not in use, or past the end of the MFT
+    MissingParent { index: usize, link: usize, parent: FileReference },
+    // The parent record now holds another file
+    StaleParent { index: usize, link: usize, parent: FileReference, found_sequence: u16 },
+    ParentNotDirectory { index: usize, link: usize, parent: usize },
+    SelfParent { index: usize, link: usize },
+    // A directory that can't be reached from the root, along with everything below it
+    Unreachable { index: usize },
+}
 
-        Ok(self.virtual_file_ptr as u64)
+impl TreeAnomaly {
+    pub fn index(&self) -> usize {
+        match self {
+            TreeAnomaly::MissingParent { index, .. }
+            | TreeAnomaly::StaleParent { index, .. }
+            | TreeAnomaly::ParentNotDirectory { index, .. }
+            | TreeAnomaly::SelfParent { index, .. }
+            | TreeAnomaly::Unreachable { index } => *index,
+        }
     }
-}
 
-impl Drop for VolumeReader {
-    fn drop(&mut self) {
-        unsafe {
-            CloseHandle(self.handle).unwrap();
+    pub fn description(&self) -> String {
+        match self {
+            TreeAnomaly::MissingParent { index, parent, .. } => {
+                format!("Record {}: parent record {} does not exist", index, parent.entry)
+            }
+            TreeAnomaly::StaleParent { index, parent, found_sequence, .. } => format!(
+                "Record {}: parent record {} was reused (sequence {}, expected {})",
+                index, parent.entry, found_sequence, parent.sequence
+            ),
+            TreeAnomaly::ParentNotDirectory { index, parent, .. } => {
+                format!("Record {}: parent record {} is not a directory", index, parent)
+            }
+            TreeAnomaly::SelfParent { index, .. } => format!("Record {}: is its own parent", index),
+            TreeAnomaly::Unreachable { index } => format!("Record {}: directory is not reachable from the root", index),
         }
     }
 }
 
-fn verify_ntfs_system_id<T: Read + Seek>(reader: &mut T) -> bool {
-    // Read 8 byte system ID, should be "NTFS    "
-    let mut buf = [0u8; 8];
-    reader.seek(Start(3)).unwrap();
-    reader.read_exact(&mut buf).unwrap();
-    from_utf8(&buf).unwrap() == "NTFS    "
+// Returned by `build_tree` in place of failing on a corrupt volume
+#[derive(Clone, Debug, Default)]
+pub struct TreeBuildReport {
+    pub anomalies: Vec<TreeAnomaly>,
+}
+
+impl TreeBuildReport {
+    pub fn is_empty(&self) -> bool {
+        self.anomalies.is_empty()
+    }
 }
 
 pub struct VolumeIndexFlatArray(pub Vec<Option<FileMetadata>>);
 
 impl VolumeIndexFlatArray {
-    pub fn from_mft_reader<T: Read + Seek>(reader: &mut T, progress_counter: Option<Arc<AtomicUsize>>) -> VolumeIndexFlatArray {
-        let mut file_metadata: Vec<Option<FileMetadata>>;
-
-        let mut mft = MftParser::from_read_seek(reader, None).unwrap();
-
-        let entry_count = mft.get_entry_count();
-        file_metadata = vec![None::<FileMetadata>; entry_count as usize];
-
-        for (index, er) in mft.iter_entries().enumerate() {
-            if let Ok(e) = er {
-
-                // Files with inode > 24 are ordinary files/directories
-                let mut name = None::<String>;
-                let mut parent_indices = BTreeSet::new();
-                let is_dir = e.header.flags.contains(EntryFlags::INDEX_PRESENT);
-                let mut file_size = 0u64;
-                let mut allocated_size = 0u64;
-                let children_indices = BTreeSet::new();
-
-                for a in e.iter_attributes().filter_map(|attr| attr.ok()) {
-                    // Filename (AttrX30) is always resident so we are fine here
-                    // If a file has hard links it has multiple filename attributes
-                    match a.data {
-                        MftAttributeContent::AttrX30(a) => {
-                            if a.namespace != DOS {
-                                parent_indices.insert(a.parent.entry as usize);
-                                name = Some(a.name);
-                            }
-                        }
-                        _ => {}
-                    }
-
-                    // Data (AttrX80) can be non-resident if it is too big for the MFT entry
-                    match a.header.type_code {
-                        MftAttributeType::DATA => {
-                            match a.header.residential_header {
-                                Resident(h) => {
-                                    file_size = h.data_size as u64;
-                                    allocated_size = h.data_size as u64;
-                                }
-                                NonResident(h) => {
-                                    // mft crate docs say that valid_data_length and allocated_length are invalid if vcn_first != 0
-                                    // assert_eq!(h.vnc_first, 0);A
-                                    file_size = h.file_size;
-                                    // When a file is compressed, allocated_length is an even multiple of the compression unit size rather than the cluster size.
-                                    allocated_size = h.allocated_length;
-                                    // Compression unit size = 2^x clusters
-                                    // println!("Compression unit size (bytes): {}", 2u32.pow(h.unit_compression_size as u32) * fs.cluster_size());
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+    // Reads the records of an extracted $MFT file in order
+    pub fn from_mft_reader<T: Read + Seek>(reader: &mut T, progress_counter: Option<Arc<AtomicUsize>>) -> Result<VolumeIndexFlatArray> {
+        let record_size = mft_dump_record_size(reader)?;
+        let entry_count = get_mft_reader_entry_count(reader)?;
+
+        let records = parse_records(entry_count, record_size, progress_counter, |f| {
+            let mut record = vec![0u8; record_size];
+            for index in 0..entry_count {
+                reader.read_exact(&mut record)?;
+                if apply_fixups(&mut record).is_ok() {
+                    f(index, &record);
                 }
+            }
+            Ok(())
+        })?;
+
+        Ok(VolumeIndexFlatArray(merge_records(records)))
+    }
+
+    pub fn from_volume_reader<R: VolumeSource>(reader: &mut R, progress_counter: Option<Arc<AtomicUsize>>) -> Result<Self> {
+        let layout = MftLayout::read(reader)?;
+        let records = parse_records(layout.record_count, layout.record_size as usize, progress_counter, |f| {
+            layout.stream_records(reader, f)
+        })?;
+
+        Ok(VolumeIndexFlatArray(merge_records(records)))
+    }
+
+    // Files that can't be attached to their parent directory are collected under a synthetic directory in
+    // the root, rather than being attached to whatever now occupies the parent's record or dropped
+    fn adopt_orphan(&mut self, orphans_directory: &mut Option<usize>, orphan: ChildLink) {
+        let root = RootDirectory as usize;
+        let index = *orphans_directory.get_or_insert_with(|| {
+            let index = self.0.len();
+            let root_reference = FileReference {
+                entry: root as u64,
+                sequence: self.0[root].as_ref().map_or(0, |r| r.sequence_number),
+            };
+
+            self.0.push(Some(FileMetadata {
+                index: index as u64,
+                links: vec![FileLink {
+                    parent: root_reference,
+                    name: String::from(ORPHANS_DIRECTORY_NAME),
+                    namespace: NAMESPACE_WIN32,
+                }],
+                is_dir: true,
+                ..Default::default()
+            }));
+            if let Some(root) = self.0[root].as_mut() {
+                root.children.push(ChildLink { index, link: 0 });
+            }
 
-                file_metadata[index] = Some(FileMetadata {
-                    name,
-                    index: index as u64,
-                    parent_indices,
-                    is_dir,
-                    file_size,
-                    allocated_size,
-                    children_indices,
-                    children_size: 0,
-                });
+            index
+        });
+
+        self.0[orphan.index].as_mut().unwrap().links[orphan.link].parent = FileReference {
+            entry: index as u64,
+            sequence: 0,
+        };
+        self.0[index].as_mut().unwrap().children.push(orphan);
+    }
+
+    fn mark_reachable(&self, from: usize, reachable: &mut [bool]) {
+        let mut stack = vec![from];
+        while let Some(i) = stack.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+
+            if let Some(f) = &self.0[i] {
+                stack.extend(f.children.iter().map(|c| c.index).filter(|c| !reachable[*c]));
             }
+        }
+    }
 
-            // Send progress update for every percentage
-            if index % (entry_count / 100) as usize == 0 {
-                if let Some(ref progress_counter) = progress_counter {
-                    progress_counter.store(index, Ordering::Relaxed);
+    // Bottom up from the directories furthest from the root. Directories are added to the parent they were first
+    // reached through, as only corruption gives one several parents. A file with several hard links is added to
+    // each directory above any of its links once, after the rest has been summed. System files (inode <= 24) are
+    // left out like the explorer leaves them out, as $MFT, $LogFile and the volume-sized $BadClus:$Bad would
+    // otherwise dwarf everything else in the root.
+    fn calculate_totals(&mut self) {
+        let root = RootDirectory as usize;
+        if !self.0.get(root).is_some_and(|r| r.is_some()) {
+            return;
+        }
+
+        let mut tree_parent = vec![usize::MAX; self.0.len()];
+        let mut order = Vec::new();
+        let mut stack = vec![root];
+        tree_parent[root] = root;
+        while let Some(i) = stack.pop() {
+            order.push(i);
+            for c in self.0[i].as_ref().unwrap().children.iter().filter(|c| c.index > 24) {
+                if self.0[c.index].as_ref().unwrap().is_dir && tree_parent[c.index] == usize::MAX {
+                    tree_parent[c.index] = i;
+                    stack.push(c.index);
                 }
             }
         }
 
-        VolumeIndexFlatArray(file_metadata)
-    }
+        let mut totals = vec![DirectoryTotals::default(); self.0.len()];
+        for &dir in &order {
+            for c in self.0[dir].as_ref().unwrap().children.iter().filter(|c| c.index > 24) {
+                let f = self.0[c.index].as_ref().unwrap();
+                if !f.is_dir && f.links.len() == 1 {
+                    totals[dir].add_file(f);
+                } else if f.is_dir && c.index != dir && tree_parent[c.index] == dir {
+                    totals[dir].dir_count += 1;
+                }
+            }
+        }
+        for &dir in order.iter().rev().filter(|d| **d != root) {
+            let t = totals[dir];
+            totals[tree_parent[dir]] += t;
+        }
 
-    pub fn from_volume_reader(reader: &mut VolumeReader, progress_counter: Option<Arc<AtomicUsize>>) -> Result<Self> {
-        assert!(verify_ntfs_system_id(reader));
+        let mut ancestors = Vec::new();
+        for f in self.0.iter().flatten().filter(|f| !f.is_dir && f.links.len() > 1 && f.index > 24) {
+            ancestors.clear();
+            for link in &f.links {
+                let mut dir = link.parent.entry as usize;
+                if tree_parent.get(dir).is_none_or(|p| *p == usize::MAX) {
+                    continue;
+                }
+                loop {
+                    ancestors.push(dir);
+                    if dir == root {
+                        break;
+                    }
+                    dir = tree_parent[dir];
+                }
+            }
+            ancestors.sort_unstable();
+            ancestors.dedup();
 
-        let fs = Ntfs::new(reader)?;
-        let file = fs.file(reader, MFT as u64)?;
-        let data = file.data(reader, "").unwrap()?;
-        let data_attr = data.to_attribute()?;
-        let mut mft_reader = data_attr.value(reader)?.attach(reader);
+            for &dir in &ancestors {
+                totals[dir].add_file(f);
+            }
+        }
 
-        Ok(Self::from_mft_reader(&mut mft_reader, progress_counter))
+        for dir in order {
+            self.0[dir].as_mut().unwrap().totals = totals[dir];
+        }
     }
 
-    pub fn build_tree(mut self) -> VolumeIndexTree {
-        let mut parent_isnt_dir_count = 0;
-        // Build tree by linking parent directories to their children
+    pub fn build_tree(mut self) -> (VolumeIndexTree, TreeBuildReport) {
+        let root = RootDirectory as usize;
+        let mut report = TreeBuildReport::default();
+        let mut orphans_directory = None;
+
+        // Build tree by linking parent directories to their children, once per hard link so each
+        // directory lists the file under the name it has there
+        let mut orphans = Vec::new();
         for i in 0..self.0.len() {
-            if self.0[i].is_some() {
-                let fm = &self.0[i].clone();
-                for parent_index in fm.clone().unwrap().parent_indices {
-                    let parent = self.0[parent_index as usize]
-                        .as_ref()
-                        .expect("File refers to nonexistent parent directory. Possible filesystem corruption detected, please run chkdsk on the drive.");
-
-                    let file_name = fm.as_ref().unwrap().name.as_deref().unwrap_or("<no name>");
-                    let file_inode = fm.as_ref().unwrap().index;
-
-                    let parent_name = parent.name.as_deref().unwrap_or("<no name>");
-                    let parent_inode = parent.index;
-
-                    if parent.is_dir
-                    {
-                        self.0[parent_index as usize]
-                            .as_mut()
-                            .unwrap()
-                            .children_indices
-                            .insert(i);
-                    } else {
-//                         println!(
-//                             "File parent isn't directory??????
-// File inode: {}
-// File name: {}
-// Parent inode: {}
-// Parent name: {}
-// ",
-//                             file_inode, file_name,
-//                             parent_inode, parent_name);
-
-                        parent_isnt_dir_count += 1;
+            let parents: Vec<FileReference> = match &self.0[i] {
+                Some(f) => f.links.iter().map(|l| l.parent).collect(),
+                None => continue,
+            };
+
+            for (link, parent) in parents.into_iter().enumerate() {
+                let anomaly = match self.0.get_mut(parent.entry as usize).and_then(|p| p.as_mut()) {
+                    None => Some(TreeAnomaly::MissingParent { index: i, link, parent }),
+                    // The parent was deleted, and its record reused for another file
+                    Some(p) if !parent.matches(p.sequence_number) => Some(TreeAnomaly::StaleParent {
+                        index: i,
+                        link,
+                        parent,
+                        found_sequence: p.sequence_number,
+                    }),
+                    // Only the root directory is its own parent
+                    Some(_) if parent.entry as usize == i && i != root => Some(TreeAnomaly::SelfParent { index: i, link }),
+                    Some(p) if !p.is_dir => Some(TreeAnomaly::ParentNotDirectory { index: i, link, parent: parent.entry as usize }),
+                    Some(p) => {
+                        p.children.push(ChildLink { index: i, link });
+                        None
                     }
+                };
+
+                if let Some(anomaly) = anomaly {
+                    report.anomalies.push(anomaly);
+                    orphans.push(ChildLink { index: i, link });
                 }
             }
         }
+        for orphan in orphans {
+            self.adopt_orphan(&mut orphans_directory, orphan);
+        }
+
+        // Directories whose parents form a loop are never reached from the root, so neither is anything in them
+        if self.0.get(root).is_some_and(|r| r.is_some()) {
+            let mut reachable = vec![false; self.0.len()];
+            self.mark_reachable(root, &mut reachable);
 
-        if parent_isnt_dir_count > 0 {
-            eprintln!("[WARN] {} files didn't have directories as parents", parent_isnt_dir_count);
+            for i in 0..self.0.len() {
+                let is_unreachable_dir = self.0[i].as_ref().is_some_and(|f| f.is_dir && !f.links.is_empty());
+                if !is_unreachable_dir || reachable[i] {
+                    continue;
+                }
+
+                report.anomalies.push(TreeAnomaly::Unreachable { index: i });
+                self.adopt_orphan(&mut orphans_directory, ChildLink { index: i, link: 0 });
+                // The orphans directory may have only just been created
+                reachable.resize(self.0.len(), true);
+                self.mark_reachable(i, &mut reachable);
+            }
         }
 
-        // let mut reverse_stack = Vec::<usize>::new();
-        // let mut queue = VecDeque::<usize>::new();
-        // let mut traversed = vec![false; self.0.len()];
-        //
-        // reverse_stack.push(RootDirectory as usize);
-        // queue.push_back(RootDirectory as usize);
-        //
-        // println!("Items in file list: {}", self.0.len());
-        // println!("Items in initial queue: {}", queue.len());
-        //
-        // let mut n = 0;
-        //
-        // while !queue.is_empty() {
-        //     let parent = queue.pop_front().unwrap();
-        //     let children = &self.0[parent].as_ref().unwrap().children_indices;
-        //
-        //     for i in children {
-        //         queue.push_back(*i);
-        //         reverse_stack.push(*i);
-        //         traversed[*i] = true;
-        //     }
-        // }
-        //
-        // println!("Traversal stack size: {}", reverse_stack.len());
-
-        // Calculate
-        VolumeIndexTree(self.0)
+        self.calculate_totals();
+
+        (VolumeIndexTree(self.0), report)
     }
 }
 
+// Deeper than any real path can be, given the 32767 character limit on Windows paths
+const MAX_PATH_DEPTH: usize = 16384;
+
 pub struct VolumeIndexTree(pub Vec<Option<FileMetadata>>);
 
 impl VolumeIndexTree {
-    pub fn dir_children(&self, inode: usize) -> Option<FilterMap<Iter<usize>, fn(&usize) -> Option<&usize>>> {
+    pub fn dir_children(&self, inode: usize) -> Option<impl Iterator<Item = &ChildLink>> {
         if let Some(file) = &self.0[inode] {
             // Files with inode > 24 are ordinary files/directories
-            return Some(file.children_indices.iter().filter_map(|i| if *i > 24 { Some(i) } else { None }));
+            return Some(file.children.iter().filter(|c| c.index > 24));
         }
 
         None
     }
+
+    // Every full path a file is reachable by, one per hard link
+    pub fn paths(&self, inode: usize) -> Vec<String> {
+        let file = match &self.0[inode] {
+            Some(f) => f,
+            None => return Vec::new(),
+        };
+
+        file.links.iter().map(|link| {
+            let mut components = vec![link.name.as_str()];
+            let mut parent = link.parent.entry as usize;
+            // Bounded in case of a directory loop, which only corruption can cause
+            for _ in 0..MAX_PATH_DEPTH {
+                if parent == RootDirectory as usize {
+                    break;
+                }
+                match self.0.get(parent).and_then(|p| p.as_ref()).and_then(|p| p.links.first()) {
+                    Some(parent_link) => {
+                        components.push(parent_link.name.as_str());
+                        parent = parent_link.parent.entry as usize;
+                    }
+                    None => {
+                        components.push("<unknown>");
+                        break;
+                    }
+                }
+            }
+
+            components.reverse();
+            format!("/{}", components.join("/"))
+        }).collect()
+    }
+}
+
+pub fn get_mft_entry_count<R: VolumeSource>(reader: &mut R) -> Result<u64> {
+    Ok(MftLayout::read(reader)?.record_count)
+}
+
+pub fn get_mft_reader_entry_count<T: Read + Seek>(reader: &mut T) -> Result<u64> {
+    let record_size = mft_dump_record_size(reader)?;
+    let length = reader.seek(End(0))?;
+    reader.seek(Start(0))?;
+
+    Ok(length / record_size as u64)
+}
+
+// An extracted $MFT has no boot sector, so the record size comes from the allocated size in the first record
+fn mft_dump_record_size<T: Read + Seek>(reader: &mut T) -> Result<usize> {
+    let mut header = [0u8; 0x20];
+    reader.seek(Start(0))?;
+    reader.read_exact(&mut header)?;
+    reader.seek(Start(0))?;
+
+    let record_size = u32::from_le_bytes(header[0x1C..0x20].try_into().unwrap()) as usize;
+    if &header[0..4] != b"FILE" || !record_size.is_power_of_two() || !(256..=65536).contains(&record_size) {
+        bail!("First MFT record has an invalid record size");
+    }
+
+    Ok(record_size)
+}
+
+// Extracted `$MFT` files (as produced by common forensic tools) start with the first file record
+// rather than a boot sector. They have all the metadata, but none of the file contents.
+pub fn is_mft_dump<T: Read + Seek>(reader: &mut T) -> bool {
+    let mut buf = [0u8; 4];
+    if reader.seek(Start(0)).is_err() || reader.read_exact(&mut buf).is_err() {
+        return false;
+    }
+    &buf == b"FILE"
 }
 
-pub fn get_mft_entry_count(reader: &mut VolumeReader) -> Result<u64> {
-    let fs = Ntfs::new(reader)?;
-    let file = fs.file(reader, MFT as u64)?;
-    let data = file.data(reader, "").unwrap()?;
-    let data_attr = data.to_attribute()?;
-    let mft_data_value = data_attr.value(reader)?.attach(reader);
-    let mft = MftParser::from_read_seek(mft_data_value, None)?;
+pub fn open_mft_dump(path: &str) -> Result<BufReader<File>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if !is_mft_dump(&mut reader) {
+        bail!("\"{}\" is not an extracted $MFT file", path);
+    }
+    reader.seek(Start(0))?;
+
+    Ok(reader)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const ROOT: usize = RootDirectory as usize;
+
+    // A file or directory linked into each of `links`, given as (parent entry, parent sequence, name)
+    fn file(index: usize, sequence_number: u16, is_dir: bool, links: &[(u64, u16, &str)]) -> FileMetadata {
+        FileMetadata {
+            index: index as u64,
+            sequence_number,
+            links: links.iter().map(|&(entry, sequence, name)| FileLink {
+                parent: reference(entry, sequence),
+                name: String::from(name),
+                namespace: NAMESPACE_WIN32,
+            }).collect(),
+            is_dir,
+            ..Default::default()
+        }
+    }
+
+    // The root plus `files`, in a flat array long enough to hold all of them
+    fn build(files: Vec<FileMetadata>) -> (VolumeIndexTree, TreeBuildReport) {
+        let mut records = vec![None; 40];
+        records[ROOT] = Some(file(ROOT, 5, true, &[(5, 5, ".")]));
+        for f in files {
+            let index = f.index as usize;
+            records[index] = Some(f);
+        }
+        VolumeIndexFlatArray(records).build_tree()
+    }
+
+    // Each child of a directory, by the name of the link that puts it there
+    fn children(tree: &VolumeIndexTree, inode: usize) -> Vec<(usize, String)> {
+        tree.0[inode].as_ref().unwrap().children.iter().map(|c| {
+            (c.index, tree.0[c.index].as_ref().unwrap().links[c.link].name.clone())
+        }).collect()
+    }
+
+    #[test]
+    fn hard_links_under_each_parent() {
+        let (tree, report) = build(vec![
+            file(30, 1, true, &[(5, 5, "a")]),
+            file(31, 1, true, &[(5, 5, "b")]),
+            file(32, 4, false, &[(30, 1, "foo.txt"), (31, 1, "bar.txt"), (30, 1, "foo again.txt")]),
+        ]);
+
+        assert!(report.is_empty());
+        assert_eq!(children(&tree, ROOT), [(5, String::from(".")), (30, String::from("a")), (31, String::from("b"))]);
+        assert_eq!(children(&tree, 30), [(32, String::from("foo.txt")), (32, String::from("foo again.txt"))]);
+        assert_eq!(children(&tree, 31), [(32, String::from("bar.txt"))]);
+        assert_eq!(tree.paths(32), ["/a/foo.txt", "/b/bar.txt", "/a/foo again.txt"]);
+    }
+    #[test]
+    fn stale_parent_goes_to_orphans() {
+        // The directory that held "x.txt" was deleted and its record reused for "new"
+        let (tree, report) = build(vec![
+            file(30, 2, true, &[(5, 5, "new")]),
+            file(31, 1, true, &[(5, 5, "kept")]),
+            file(32, 1, false, &[(30, 1, "x.txt"), (31, 1, "y.txt")]),
+        ]);
+
+        assert_eq!(report.anomalies, [TreeAnomaly::StaleParent { index: 32, link: 0, parent: reference(30, 1), found_sequence: 2 }]);
+        let orphans = 40;
+        assert_eq!(children(&tree, ROOT), [
+            (5, String::from(".")),
+            (30, String::from("new")),
+            (31, String::from("kept")),
+            (orphans, String::from(ORPHANS_DIRECTORY_NAME)),
+        ]);
+        assert_eq!(children(&tree, orphans), [(32, String::from("x.txt"))]);
+        assert!(children(&tree, 30).is_empty());
+        assert_eq!(children(&tree, 31), [(32, String::from("y.txt"))]);
+        assert_eq!(tree.paths(32), ["/$Orphans/x.txt", "/kept/y.txt"]);
+    }
+    #[test]
+    fn missing_parent() {
+        // Record 35 is not in use, and 100 is past the end of the MFT
+        let (tree, report) = build(vec![
+            file(30, 1, false, &[(35, 1, "a.txt")]),
+            file(31, 1, false, &[(100, 1, "b.txt")]),
+        ]);
+
+        assert_eq!(report.anomalies, [
+            TreeAnomaly::MissingParent { index: 30, link: 0, parent: reference(35, 1) },
+            TreeAnomaly::MissingParent { index: 31, link: 0, parent: reference(100, 1) },
+        ]);
+        assert_eq!(children(&tree, ROOT), [(5, String::from(".")), (40, String::from(ORPHANS_DIRECTORY_NAME))]);
+        assert_eq!(children(&tree, 40), [(30, String::from("a.txt")), (31, String::from("b.txt"))]);
+    }
 
-    Ok(mft.get_entry_count())
-}
\ No newline at end of file
+    #[test]
+    fn parent_not_directory() {
+        let (tree, report) = build(vec![
+            file(30, 1, false, &[(5, 5, "a.txt")]),
+            file(31, 1, false, &[(30, 1, "b.txt")]),
+        ]);
+
+        assert_eq!(report.anomalies, [TreeAnomaly::ParentNotDirectory { index: 31, link: 0, parent: 30 }]);
+        assert_eq!(children(&tree, ROOT), [
+            (5, String::from(".")),
+            (30, String::from("a.txt")),
+            (40, String::from(ORPHANS_DIRECTORY_NAME)),
+        ]);
+        assert!(children(&tree, 30).is_empty());
+        assert_eq!(children(&tree, 40), [(31, String::from("b.txt"))]);
+    }
+
+    #[test]
+    fn self_parent() {
+        let (tree, report) = build(vec![
+            file(30, 1, true, &[(30, 1, "loop")]),
+            file(31, 1, false, &[(30, 1, "a.txt")]),
+        ]);
+
+        assert_eq!(report.anomalies, [TreeAnomaly::SelfParent { index: 30, link: 0 }]);
+        assert_eq!(children(&tree, ROOT), [(5, String::from(".")), (40, String::from(ORPHANS_DIRECTORY_NAME))]);
+        assert_eq!(children(&tree, 40), [(30, String::from("loop"))]);
+        // What's inside is still reachable through the orphaned directory
+        assert_eq!(children(&tree, 30), [(31, String::from("a.txt"))]);
+        assert_eq!(tree.paths(31), ["/$Orphans/loop/a.txt"]);
+    }
+
+    #[test]
+    fn unreachable_directory_loop() {
+        // Two directories that are each other's parent, with a file inside
+        let (tree, report) = build(vec![
+            file(30, 1, true, &[(31, 1, "a")]),
+            file(31, 1, true, &[(30, 1, "b")]),
+            file(32, 1, false, &[(31, 1, "c.txt")]),
+        ]);
+
+        // Only the first directory of the loop is reported, as moving it makes the rest reachable
+        assert_eq!(report.anomalies, [TreeAnomaly::Unreachable { index: 30 }]);
+        assert_eq!(children(&tree, ROOT), [(5, String::from(".")), (40, String::from(ORPHANS_DIRECTORY_NAME))]);
+        assert_eq!(children(&tree, 40), [(30, String::from("a"))]);
+        assert_eq!(children(&tree, 30), [(31, String::from("b"))]);
+        assert_eq!(children(&tree, 31), [(30, String::from("a")), (32, String::from("c.txt"))]);
+        assert_eq!(tree.paths(32), ["/$Orphans/a/b/c.txt"]);
+    }
+    #[test]
+    fn directory_totals() {
+        let mut v = varied_volume();
+        // A stand-in for $BadClus, whose $Bad stream is as large as the volume
+        v.set_record(8, record(1, IN_USE, FileReference::default(), &[
+            resident(0x30, "", &file_name(reference(5, 5), "$BadClus", 3)),
+            non_resident(&NonResident::new(0x80, "$Bad", vec![(64, None)], 64 * CLUSTER_SIZE as u64)),
+        ]));
+        v.set_record(28, record(1, IN_USE | DIRECTORY, FileReference::default(), &[
+            resident(0x30, "", &file_name(reference(5, 5), "other", 1)),
+        ]));
+        // Linked in the sibling directories docs and other
+        v.set_record(29, record(1, IN_USE, FileReference::default(), &[
+            resident(0x30, "", &file_name(reference(30, 1), "shared.txt", 1)),
+            resident(0x30, "", &file_name(reference(28, 1), "shared.txt", 1)),
+            resident(0x80, "", b"0123456789"),
+        ]));
+        // Linked twice in docs
+        v.set_record(25, record(1, IN_USE, FileReference::default(), &[
+            resident(0x30, "", &file_name(reference(30, 1), "x1", 1)),
+            resident(0x30, "", &file_name(reference(30, 1), "x2", 1)),
+            resident(0x80, "", b"abc"),
+        ]));
+        let mut disk = std::io::Cursor::new(v.finish());
+        let (tree, report) = VolumeIndexFlatArray::from_volume_reader(&mut disk, None).unwrap().build_tree();
+        assert!(report.is_empty());
+        let totals = |inode: usize| tree.0[inode].as_ref().unwrap().totals;
+
+        // a.txt, both streams of b.bin, shared.txt and x1/x2. Resident streams are allocated as much as their size.
+        let docs_size = 11 + 5000 + 14 + 10 + 3;
+        let docs_allocated = docs_size - 5000 + 8192;
+        assert_eq!(totals(30), DirectoryTotals { size: docs_size, allocated_size: docs_allocated, file_count: 4, dir_count: 0 });
+        assert_eq!(totals(28), DirectoryTotals { size: 10, allocated_size: 10, file_count: 1, dir_count: 0 });
+        // b.bin and shared.txt count once however many of their links are below the root, and the link adds no
+        // size. $MFT and $BadClus aren't counted at all.
+        assert_eq!(totals(ROOT), DirectoryTotals { size: docs_size, allocated_size: docs_allocated, file_count: 5, dir_count: 2 });
+        assert_eq!(totals(31), DirectoryTotals::default());
+    }
+    #[test]
+    fn mft_dump() {
+        let mut v = basic_volume();
+        // The update sequence number at the end of the first stride no longer matches
+        let mut broken = record(1, IN_USE, FileReference::default(), &[
+            resident(0x30, "", &file_name(reference(5, 5), "broken.txt", 1)),
+        ]);
+        broken[510] ^= 0xFF;
+        v.set_record(26, broken);
+        let dump: Vec<u8> = v.records.iter().flat_map(|r| r.clone().unwrap_or_else(|| vec![0; RECORD_SIZE])).collect();
+        let mut reader = std::io::Cursor::new(dump);
+
+        assert!(is_mft_dump(&mut reader));
+        assert_eq!(mft_dump_record_size(&mut reader).unwrap(), RECORD_SIZE);
+        assert_eq!(get_mft_reader_entry_count(&mut reader).unwrap(), 32);
+
+        let flat = VolumeIndexFlatArray::from_mft_reader(&mut reader, None).unwrap();
+        assert_eq!(flat.0.len(), 32);
+        // Only $MFT, the root, docs and a.txt are left, without the broken record or the unused ones
+        assert_eq!(flat.0.iter().filter(|r| r.is_none()).count(), 28);
+        assert!(flat.0[26].is_none());
+
+        let (tree, report) = flat.build_tree();
+        assert!(report.is_empty());
+        assert_eq!(children(&tree, 30), [(31, String::from("a.txt"))]);
+        assert_eq!(tree.paths(31), ["/docs/a.txt"]);
+        assert_eq!(tree.0[31].as_ref().unwrap().file_size, 11);
+
+        // A whole volume starts with a boot sector instead
+        let mut volume = std::io::Cursor::new(basic_volume().finish());
+        assert!(!is_mft_dump(&mut volume));
+        assert!(mft_dump_record_size(&mut volume).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use crate::bytes::le_u16;
+
+// LZNT1 compresses data in independent chunks of up to this much
+pub const LZNT1_CHUNK_SIZE: usize = 4096;
+
+const CHUNK_COMPRESSED: u16 = 0x8000;
+const CHUNK_LENGTH_MASK: u16 = 0x0FFF;
+
+// Decompresses one NTFS compression unit into `output`, which is the unit's uncompressed size. Each chunk
+// decompresses to the next 4 KB of `output`, and anything the chunks don't fill is zero.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<()> {
+    output.fill(0);
+
+    let mut position = 0;
+    let mut output_start = 0;
+    while position + 2 <= input.len() && output_start < output.len() {
+        let header = le_u16(input, position);
+        // A zero header ends the data early
+        if header == 0 {
+            break;
+        }
+
+        let length = (header & CHUNK_LENGTH_MASK) as usize + 1;
+        let data = position + 2;
+        if data + length > input.len() {
+            bail!("LZNT1 chunk is truncated");
+        }
+
+        let chunk = &input[data..data + length];
+        let output_end = (output_start + LZNT1_CHUNK_SIZE).min(output.len());
+        let out = &mut output[output_start..output_end];
+        if header & CHUNK_COMPRESSED != 0 {
+            decompress_chunk(chunk, out)?;
+        } else {
+            let len = chunk.len().min(out.len());
+            out[..len].copy_from_slice(&chunk[..len]);
+        }
+
+        position = data + length;
+        output_start += LZNT1_CHUNK_SIZE;
+    }
+
+    Ok(())
+}
+
+// A flag byte precedes every 8 tokens, each bit saying whether its token is a literal byte or a 16 bit
+// back-reference packing an offset and a length
+fn decompress_chunk(chunk: &[u8], out: &mut [u8]) -> Result<()> {
+    let mut position = 0;
+    let mut written = 0;
+
+    while position < chunk.len() {
+        let flags = chunk[position];
+        position += 1;
+
+        for bit in 0..8 {
+            if position >= chunk.len() {
+                break;
+            }
+
+            if flags & (1 << bit) == 0 {
+                if written >= out.len() {
+                    bail!("LZNT1 chunk decompresses past 4 KB");
+                }
+                out[written] = chunk[position];
+                written += 1;
+                position += 1;
+                continue;
+            }
+
+            if position + 2 > chunk.len() || written == 0 {
+                bail!("LZNT1 back-reference is invalid");
+            }
+            let token = le_u16(chunk, position);
+            position += 2;
+
+            // Offsets can only reach back to the start of the chunk, so the further into it, the more bits
+            // the offset takes from the length
+            let mut length_bits = 12;
+            let mut reach = written - 1;
+            while reach >= 0x10 {
+                reach >>= 1;
+                length_bits -= 1;
+            }
+            let offset = (token >> length_bits) as usize + 1;
+            let length = (token & ((1 << length_bits) - 1)) as usize + 3;
+            if offset > written || written + length > out.len() {
+                bail!("LZNT1 back-reference is out of bounds");
+            }
+
+            // Byte by byte, as the source may overlap what is being written
+            for _ in 0..length {
+                out[written] = out[written - offset];
+                written += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lznt1_compress;
+
+    #[test]
+    fn overlapping_back_reference() {
+        // "abc", then 9 bytes from 3 back
+        let input = [0x05, 0xB0, 0x08, b'a', b'b', b'c', 0x06, 0x20];
+        let mut output = [0xFF; 16];
+        decompress(&input, &mut output).unwrap();
+        assert_eq!(output[..12], *b"abcabcabcabc");
+        assert_eq!(output[12..], [0; 4]);
+    }
+
+    #[test]
+    fn round_trip() {
+        // Repetitive enough to compress, but with back-references at every offset width
+        let data: Vec<u8> = (0..3 * LZNT1_CHUNK_SIZE + 100).map(|i| b"the quick brown fox "[i % 20] ^ (i / 777) as u8).collect();
+        let compressed = lznt1_compress(&data);
+        assert!(compressed.len() < data.len() / 4);
+
+        let mut output = vec![0xFF; 4 * LZNT1_CHUNK_SIZE];
+        decompress(&compressed, &mut output).unwrap();
+        assert_eq!(output[..data.len()], data[..]);
+        assert!(output[data.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn uncompressed_chunks_and_early_end() {
+        let mut input = vec![0xFF, 0x3F];
+        input.extend_from_slice(&[9; LZNT1_CHUNK_SIZE]);
+        input.extend_from_slice(&[0, 0]);
+        input.extend_from_slice(&lznt1_compress(&[1; 100]));
+
+        let mut output = vec![0xFF; 2 * LZNT1_CHUNK_SIZE];
+        decompress(&input, &mut output).unwrap();
+        assert!(output[..LZNT1_CHUNK_SIZE].iter().all(|b| *b == 9));
+        assert!(output[LZNT1_CHUNK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn corrupt_chunks() {
+        let mut output = [0u8; LZNT1_CHUNK_SIZE];
+        // Truncated, a back-reference before anything was written, and one reaching before the chunk
+        assert!(decompress(&[0x05, 0xB0, 0x08], &mut output).is_err());
+        assert!(decompress(&[0x02, 0xB0, 0x01, 0x00, 0x00], &mut output).is_err());
+        assert!(decompress(&[0x03, 0xB0, 0x02, b'a', 0x00, 0x20], &mut output).is_err());
+        // Decompressing past the end of the output
+        assert!(decompress(&lznt1_compress(&[1; LZNT1_CHUNK_SIZE]), &mut output[..LZNT1_CHUNK_SIZE - 1]).is_err());
+    }
+}
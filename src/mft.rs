@@ -0,0 +1,502 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use anyhow::{bail, Result};
+use crate::boot::BootSector;
+use crate::record::{apply_fixups, parse_attribute_list, Attribute, DataRun, FileRecord, ATTR_ATTRIBUTE_LIST, ATTR_DATA};
+use crate::volume::VolumeSource;
+
+// Records are read this many bytes at a time, which keeps a spinning disk streaming
+pub const MFT_CHUNK_SIZE: usize = 4 * 2usize.pow(20); // 4 MB
+
+// A contiguous piece of the $MFT data, mapping a byte range of the MFT to a byte offset on the volume
+#[derive(Clone, Copy, Debug)]
+pub struct MftExtent {
+    pub mft_offset: u64,
+    pub volume_offset: u64,
+    pub length: u64,
+}
+
+// Where every record of the MFT lives on the volume, from the $DATA runs of $MFT itself
+pub struct MftLayout {
+    pub boot_sector: BootSector,
+    pub record_size: u32,
+    pub record_count: u64,
+    // In MFT order
+    pub extents: Vec<MftExtent>,
+}
+
+impl MftLayout {
+    pub fn read<R: VolumeSource>(reader: &mut R) -> Result<Self> {
+        let boot_sector = BootSector::read(reader)?;
+        let cluster_size = boot_sector.cluster_size();
+        let record_size = boot_sector.file_record_size;
+
+        // Record 0 is $MFT itself, and the boot sector tells us where it starts
+        let mut record = vec![0u8; record_size as usize];
+        reader.seek(SeekFrom::Start(boot_sector.mft_offset()))?;
+        reader.read_exact(&mut record)?;
+        apply_fixups(&mut record)?;
+        let mft_record = FileRecord::new(&record)?;
+
+        let mut data_size = None;
+        // Pieces of the $DATA attribute as (lowest VCN, runs)
+        let mut pieces = Vec::new();
+        let mut attribute_list = None;
+        for attribute in mft_record.attributes() {
+            let attribute = attribute?;
+            match attribute.type_code() {
+                ATTR_DATA if attribute.is_unnamed() => {
+                    if attribute.lowest_vcn() == 0 {
+                        data_size = Some(attribute.data_size());
+                    }
+                    pieces.push((attribute.lowest_vcn(), attribute.data_runs()?));
+                }
+                ATTR_ATTRIBUTE_LIST => {
+                    attribute_list = Some(if attribute.is_non_resident() {
+                        read_runs(reader, &attribute.data_runs()?, cluster_size, attribute.data_size())?
+                    } else {
+                        attribute.value()?.to_vec()
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let data_size = match data_size {
+            Some(s) => s,
+            None => bail!("$MFT has no $DATA attribute"),
+        };
+
+        let mut layout = MftLayout {
+            boot_sector,
+            record_size,
+            record_count: data_size / record_size as u64,
+            extents: Vec::new(),
+        };
+        layout.extents = runs_to_extents(&pieces, cluster_size, data_size);
+
+        // A very fragmented MFT has more runs than fit in record 0, and lists the extension records holding the
+        // rest. Those records are always within the part of the MFT that record 0 maps.
+        if let Some(attribute_list) = attribute_list {
+            for entry in parse_attribute_list(&attribute_list)? {
+                let entry_record = entry.reference.entry;
+                if entry.type_code != ATTR_DATA || !entry.name.is_empty() || entry_record == 0 {
+                    continue;
+                }
+
+                let record = layout.read_record(reader, entry_record)?;
+                for attribute in FileRecord::new(&record)?.attributes() {
+                    let attribute = attribute?;
+                    if attribute.type_code() == ATTR_DATA && attribute.is_unnamed() && attribute.lowest_vcn() == entry.lowest_vcn {
+                        pieces.push((attribute.lowest_vcn(), attribute.data_runs()?));
+                    }
+                }
+            }
+
+            pieces.sort_by_key(|(vcn, _)| *vcn);
+            pieces.dedup_by_key(|(vcn, _)| *vcn);
+            layout.extents = runs_to_extents(&pieces, cluster_size, data_size);
+        }
+
+        Ok(layout)
+    }
+
+    // Reads one record and applies its fixups, even if it straddles two extents
+    pub fn read_record<R: VolumeSource>(&self, reader: &mut R, index: u64) -> Result<Vec<u8>> {
+        if index >= self.record_count {
+            bail!("MFT record {} is past the end of the MFT", index);
+        }
+
+        let mut record = vec![0u8; self.record_size as usize];
+        let start = index * self.record_size as u64;
+        let mut filled = 0;
+        while filled < record.len() {
+            let position = start + filled as u64;
+            let extent = match self.extents.iter().find(|e| (e.mft_offset..e.mft_offset + e.length).contains(&position)) {
+                Some(e) => e,
+                None => bail!("MFT record {} is not mapped to the volume", index),
+            };
+
+            let len = (record.len() - filled).min((extent.mft_offset + extent.length - position) as usize);
+            reader.seek(SeekFrom::Start(extent.volume_offset + position - extent.mft_offset))?;
+            reader.read_exact(&mut record[filled..filled + len])?;
+            filled += len;
+        }
+
+        apply_fixups(&mut record)?;
+        Ok(record)
+    }
+
+    // Reads every record, calling `f` with its index and fixed up bytes. Extents are read in large sequential
+    // chunks in the order they sit on the volume, so records arrive out of order if the MFT is fragmented.
+    // Records that fail their fixups (never written, or torn) are skipped.
+    pub fn stream_records<R: VolumeSource, F: FnMut(u64, &[u8])>(&self, reader: &mut R, mut f: F) -> Result<()> {
+        let record_size = self.record_size as u64;
+        let mut extents = self.extents.clone();
+        extents.sort_by_key(|e| e.volume_offset);
+
+        let volume_extents: Vec<Range<u64>> = extents.iter().map(|e| e.volume_offset..e.volume_offset + e.length).collect();
+        reader.read_ahead(&volume_extents);
+
+        let chunk_records = (MFT_CHUNK_SIZE as u64 / record_size).max(1);
+        let mut chunk = vec![0u8; (chunk_records * record_size) as usize];
+        // Records split across two extents, read separately at the end
+        let mut straddling = Vec::new();
+
+        for extent in &extents {
+            let end = (extent.mft_offset + extent.length).min(self.record_count * record_size);
+            let first_record = extent.mft_offset.div_ceil(record_size);
+            let last_record = end / record_size;
+            if extent.mft_offset % record_size != 0 {
+                straddling.push(first_record - 1);
+            }
+
+            let mut index = first_record;
+            while index < last_record {
+                let count = chunk_records.min(last_record - index);
+                let buf = &mut chunk[..(count * record_size) as usize];
+                reader.seek(SeekFrom::Start(extent.volume_offset + index * record_size - extent.mft_offset))?;
+                reader.read_exact(buf)?;
+
+                for (i, record) in buf.chunks_exact_mut(record_size as usize).enumerate() {
+                    if apply_fixups(record).is_ok() {
+                        f(index + i as u64, record);
+                    }
+                }
+                index += count;
+            }
+        }
+
+        straddling.sort();
+        straddling.dedup();
+        for index in straddling {
+            if let Ok(record) = self.read_record(reader, index) {
+                f(index, &record);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Finds one of a file's attributes by type and name, gathering the pieces of a large non-resident attribute
+    // from the extension records its $ATTRIBUTE_LIST points to
+    pub fn read_attribute<R: VolumeSource>(&self, reader: &mut R, index: u64, type_code: u32, name: &str) -> Result<Option<AttributeValue>> {
+        let record = self.read_record(reader, index)?;
+        let record = FileRecord::new(&record)?;
+
+        // From the first piece, which is the only one with valid sizes
+        let mut first = None;
+        let mut pieces = Vec::new();
+        let mut attribute_list = None;
+        for attribute in record.attributes() {
+            let attribute = attribute?;
+            if attribute.type_code() == type_code && attribute.name()? == name {
+                if !attribute.is_non_resident() {
+                    return Ok(Some(AttributeValue::Resident(attribute.value()?.to_vec())));
+                }
+                if attribute.lowest_vcn() == 0 {
+                    first = Some(NonResidentValue::from_first_piece(&attribute));
+                }
+                pieces.push((attribute.lowest_vcn(), attribute.data_runs()?));
+            } else if attribute.type_code() == ATTR_ATTRIBUTE_LIST {
+                attribute_list = Some(if attribute.is_non_resident() {
+                    read_runs(reader, &attribute.data_runs()?, self.boot_sector.cluster_size(), attribute.data_size())?
+                } else {
+                    attribute.value()?.to_vec()
+                });
+            }
+        }
+
+        if let Some(attribute_list) = attribute_list {
+            for entry in parse_attribute_list(&attribute_list)? {
+                if entry.type_code != type_code || entry.name != name || entry.reference.entry == index {
+                    continue;
+                }
+
+                let extension = self.read_record(reader, entry.reference.entry)?;
+                let extension = FileRecord::new(&extension)?;
+                // A reused record that now belongs to another file, or to a later file in this record
+                let base_reference = extension.base_reference();
+                if base_reference.entry != index || !base_reference.matches(record.sequence_number()) {
+                    continue;
+                }
+                for attribute in extension.attributes() {
+                    let attribute = attribute?;
+                    if attribute.type_code() == type_code && attribute.name()? == name && attribute.lowest_vcn() == entry.lowest_vcn {
+                        if attribute.lowest_vcn() == 0 {
+                            first = Some(NonResidentValue::from_first_piece(&attribute));
+                        }
+                        pieces.push((attribute.lowest_vcn(), attribute.data_runs()?));
+                    }
+                }
+            }
+        }
+
+        if pieces.is_empty() {
+            return Ok(None);
+        }
+        let mut value = match first {
+            Some(v) => v,
+            None => bail!("Record {} is missing the first piece of an attribute", index),
+        };
+
+        pieces.sort_by_key(|(vcn, _)| *vcn);
+        pieces.dedup_by_key(|(vcn, _)| *vcn);
+        value.runs = pieces.into_iter().flat_map(|(_, runs)| runs).collect();
+        Ok(Some(AttributeValue::NonResident(value)))
+    }
+}
+
+pub enum AttributeValue {
+    Resident(Vec<u8>),
+    NonResident(NonResidentValue),
+}
+
+pub struct NonResidentValue {
+    pub data_size: u64,
+    pub initialized_size: u64,
+    pub compressed: bool,
+    pub encrypted: bool,
+    // Log2 of the compression unit size in clusters
+    pub compression_unit: u8,
+    // Runs of every piece, in VCN order
+    pub runs: Vec<DataRun>,
+}
+
+impl NonResidentValue {
+    fn from_first_piece(attribute: &Attribute) -> Self {
+        NonResidentValue {
+            data_size: attribute.data_size(),
+            initialized_size: attribute.initialized_size(),
+            compressed: attribute.is_compressed(),
+            encrypted: attribute.is_encrypted(),
+            compression_unit: attribute.compression_unit(),
+            runs: Vec::new(),
+        }
+    }
+}
+
+fn runs_to_extents(pieces: &[(u64, Vec<DataRun>)], cluster_size: u64, data_size: u64) -> Vec<MftExtent> {
+    let mut extents = Vec::new();
+
+    for (lowest_vcn, runs) in pieces {
+        let mut vcn = *lowest_vcn;
+        for run in runs {
+            let mft_offset = vcn * cluster_size;
+            vcn += run.length;
+            // The MFT is never sparse, but allocated space past the data size isn't records
+            if let Some(lcn) = run.lcn {
+                if mft_offset < data_size {
+                    extents.push(MftExtent {
+                        mft_offset,
+                        volume_offset: lcn * cluster_size,
+                        length: (run.length * cluster_size).min(data_size - mft_offset),
+                    });
+                }
+            }
+        }
+    }
+
+    extents
+}
+
+// Reads a small non-resident attribute whole, with sparse runs as zeros
+pub(crate) fn read_runs<R: VolumeSource>(reader: &mut R, runs: &[DataRun], cluster_size: u64, size: u64) -> Result<Vec<u8>> {
+    // A corrupt size can't allocate more than the runs map, which reading checks against the volume
+    let mapped = runs.iter().fold(0u64, |total, run| total.saturating_add(run.length.saturating_mul(cluster_size)));
+    let mut buf = Vec::with_capacity(size.min(mapped) as usize);
+
+    for run in runs {
+        let start = buf.len();
+        let len = run.length.saturating_mul(cluster_size).min(size - start as u64) as usize;
+        buf.resize(start + len, 0);
+        if let Some(lcn) = run.lcn {
+            reader.seek(SeekFrom::Start(lcn * cluster_size))?;
+            reader.read_exact(&mut buf[start..])?;
+        }
+        if buf.len() as u64 == size {
+            break;
+        }
+    }
+
+    if (buf.len() as u64) < size {
+        bail!("Data runs are shorter than the attribute");
+    }
+
+    Ok(buf)
+}
+
+// Reads `buf.len()` bytes starting `offset` bytes into the data the runs map. Sparse runs, and anything past
+// the end of the runs, read as zeros.
+pub(crate) fn read_runs_at<R: VolumeSource>(reader: &mut R, runs: &[DataRun], cluster_size: u64, offset: u64, buf: &mut [u8]) -> Result<()> {
+    buf.fill(0);
+    let end = offset + buf.len() as u64;
+
+    let mut run_start = 0;
+    for run in runs {
+        let run_end = run_start + run.length * cluster_size;
+        if run_end > offset && run_start < end {
+            let from = run_start.max(offset);
+            let to = run_end.min(end);
+            if let Some(lcn) = run.lcn {
+                reader.seek(SeekFrom::Start(lcn * cluster_size + from - run_start))?;
+                reader.read_exact(&mut buf[(from - offset) as usize..(to - offset) as usize])?;
+            }
+        }
+        if run_end >= end {
+            break;
+        }
+        run_start = run_end;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::record::{FileReference, ATTR_FILE_NAME};
+    use crate::testing::*;
+
+    #[test]
+    fn layout_of_a_fragmented_mft() {
+        let mut volume = Cursor::new(basic_volume().finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+        assert_eq!((layout.record_size, layout.record_count), (1024, 32));
+
+        let extents: Vec<_> = layout.extents.iter().map(|e| (e.mft_offset, e.volume_offset, e.length)).collect();
+        assert_eq!(extents, [(0, 20 * 4096, 16384), (16384, 8 * 4096, 16384)]);
+
+        let record = layout.read_record(&mut volume, 31).unwrap();
+        assert_eq!(FileRecord::new(&record).unwrap().sequence_number(), 2);
+        assert!(layout.read_record(&mut volume, 32).is_err());
+    }
+
+    #[test]
+    fn records_stream_in_volume_order() {
+        let mut volume = Cursor::new(basic_volume().finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+
+        // Records that were never written fail their fixups and are skipped
+        let mut indexes = Vec::new();
+        layout.stream_records(&mut volume, |index, record| {
+            assert_eq!(&layout.read_record(&mut Cursor::new(basic_volume().finish()), index).unwrap()[..], record);
+            indexes.push(index);
+        }).unwrap();
+        assert_eq!(indexes, [30, 31, 0, 5]);
+    }
+
+    #[test]
+    fn mft_runs_from_an_attribute_list() {
+        // Record 0 only maps the first 4 clusters, and record 1 holds the runs of the rest
+        let mut v = TestVolume::new(64, vec![(20, 4), (8, 4)]);
+        let mut first = NonResident::new(ATTR_DATA, "", vec![(4, Some(20))], 32 * RECORD_SIZE as u64);
+        first.allocated_size = 8 * CLUSTER_SIZE as u64;
+        let mut second = NonResident::new(ATTR_DATA, "", vec![(4, Some(8))], 0);
+        second.lowest_vcn = 4;
+        let list = [
+            attribute_list_entry(ATTR_FILE_NAME, "", 0, reference(0, 1)),
+            attribute_list_entry(ATTR_DATA, "", 0, reference(0, 1)),
+            attribute_list_entry(ATTR_DATA, "", 4, reference(1, 1)),
+        ].concat();
+        v.set_record(0, record(1, IN_USE, FileReference::default(), &[
+            resident(ATTR_ATTRIBUTE_LIST, "", &list),
+            resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), "$MFT", 3)),
+            non_resident(&first),
+        ]));
+        v.set_record(1, record(1, IN_USE, reference(0, 1), &[non_resident(&second)]));
+        v.set_record(31, record(7, IN_USE, FileReference::default(), &[]));
+
+        let mut volume = Cursor::new(v.finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+        let extents: Vec<_> = layout.extents.iter().map(|e| (e.mft_offset, e.volume_offset)).collect();
+        assert_eq!(extents, [(0, 20 * 4096), (16384, 8 * 4096)]);
+        let record = layout.read_record(&mut volume, 31).unwrap();
+        assert_eq!(FileRecord::new(&record).unwrap().sequence_number(), 7);
+    }
+
+    #[test]
+    fn mft_without_data() {
+        let mut v = basic_volume();
+        v.set_record(0, record(1, IN_USE, FileReference::default(), &[]));
+        assert!(MftLayout::read(&mut Cursor::new(v.finish())).is_err());
+    }
+
+    #[test]
+    fn small_non_resident_values() {
+        let mut v = TestVolume::new(16, vec![(4, 1)]);
+        v.write(10, &[1; CLUSTER_SIZE]);
+        v.write(11, &[2; 100]);
+        let mut volume = Cursor::new(v.finish());
+        let runs = [DataRun { lcn: Some(11), length: 1 }, DataRun { lcn: None, length: 1 }, DataRun { lcn: Some(10), length: 2 }];
+
+        let value = read_runs(&mut volume, &runs, CLUSTER_SIZE as u64, 9000).unwrap();
+        assert_eq!(value.len(), 9000);
+        assert_eq!(value[..100], [2; 100]);
+        assert!(value[100..8192].iter().all(|b| *b == 0));
+        assert!(value[8192..].iter().all(|b| *b == 1));
+
+        assert!(read_runs(&mut volume, &runs[..1], CLUSTER_SIZE as u64, 9000).is_err());
+        // A corrupt size only allocates what the runs map before failing
+        assert!(read_runs(&mut volume, &runs[..1], CLUSTER_SIZE as u64, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn attributes_spread_over_extension_records() {
+        let mut v = basic_volume();
+        v.write(40, &[1; 2 * CLUSTER_SIZE]);
+        v.write(44, &[2; 2 * CLUSTER_SIZE]);
+        let mut first = NonResident::new(ATTR_DATA, "big", vec![(2, Some(40))], 4 * CLUSTER_SIZE as u64 - 100);
+        first.allocated_size = 4 * CLUSTER_SIZE as u64;
+        let mut second = NonResident::new(ATTR_DATA, "big", vec![(2, Some(44))], 0);
+        second.lowest_vcn = 2;
+        let list = [
+            attribute_list_entry(ATTR_DATA, "big", 0, reference(26, 1)),
+            // An extension left over from an earlier file in record 26, and another file's record, which are passed over
+            attribute_list_entry(ATTR_DATA, "big", 2, reference(28, 1)),
+            attribute_list_entry(ATTR_DATA, "big", 2, reference(31, 2)),
+            attribute_list_entry(ATTR_DATA, "big", 2, reference(27, 1)),
+        ].concat();
+        v.set_record(26, record(1, IN_USE, FileReference::default(), &[
+            resident(ATTR_ATTRIBUTE_LIST, "", &list),
+            resident(ATTR_DATA, "", b"small"),
+            non_resident(&first),
+        ]));
+        v.set_record(27, record(1, IN_USE, reference(26, 1), &[non_resident(&second)]));
+        let mut stale = NonResident::new(ATTR_DATA, "big", vec![(2, Some(50))], 0);
+        stale.lowest_vcn = 2;
+        v.set_record(28, record(1, IN_USE, reference(26, 9), &[non_resident(&stale)]));
+        let mut volume = Cursor::new(v.finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+
+        let big = match layout.read_attribute(&mut volume, 26, ATTR_DATA, "big").unwrap() {
+            Some(AttributeValue::NonResident(big)) => big,
+            _ => panic!("expected a non-resident attribute"),
+        };
+        assert_eq!(big.data_size, 4 * CLUSTER_SIZE as u64 - 100);
+        assert_eq!(big.runs, [DataRun { lcn: Some(40), length: 2 }, DataRun { lcn: Some(44), length: 2 }]);
+        assert!(!big.compressed && !big.encrypted);
+
+        assert!(matches!(layout.read_attribute(&mut volume, 26, ATTR_DATA, "").unwrap(), Some(AttributeValue::Resident(v)) if v == b"small"));
+        assert!(layout.read_attribute(&mut volume, 26, ATTR_DATA, "other").unwrap().is_none());
+    }
+
+    #[test]
+    fn reading_within_runs() {
+        let mut v = TestVolume::new(16, vec![(4, 1)]);
+        v.write(10, &[1; CLUSTER_SIZE]);
+        v.write(11, &[2; CLUSTER_SIZE]);
+        let mut volume = Cursor::new(v.finish());
+        let runs = [DataRun { lcn: Some(10), length: 1 }, DataRun { lcn: None, length: 1 }, DataRun { lcn: Some(11), length: 1 }];
+
+        // Across a sparse run, and on past the end of the runs
+        let mut buf = vec![0xFF; 3 * CLUSTER_SIZE];
+        read_runs_at(&mut volume, &runs, CLUSTER_SIZE as u64, 4000, &mut buf).unwrap();
+        assert_eq!(buf[..96], [1; 96]);
+        assert!(buf[96..96 + CLUSTER_SIZE].iter().all(|b| *b == 0));
+        assert!(buf[96 + CLUSTER_SIZE..96 + 2 * CLUSTER_SIZE].iter().all(|b| *b == 2));
+        assert!(buf[96 + 2 * CLUSTER_SIZE..].iter().all(|b| *b == 0));
+    }
+}
+
@@ -0,0 +1,402 @@
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use anyhow::Result;
+use crate::mft::{read_runs, AttributeValue, MftLayout};
+use crate::record::{parse_attribute_list, FileAttributes, FileName, FileReference, FileRecord, StandardInformation, ATTR_ATTRIBUTE_LIST, ATTR_DATA, ATTR_FILE_NAME, ATTR_REPARSE_POINT, ATTR_STANDARD_INFORMATION, NAMESPACE_DOS};
+use crate::reparse::ReparsePoint;
+use crate::time::Timestamps;
+use crate::volume::VolumeSource;
+use crate::{DataStream, FileLink, FileMetadata};
+
+// What a single MFT record says about its file. A file whose attributes don't fit in one record has
+// extension records, which only make sense once merged into their base record.
+pub(crate) struct RecordInfo {
+    sequence_number: u16,
+    // Null for base records
+    base_reference: FileReference,
+    is_dir: bool,
+    standard_information_times: Timestamps,
+    file_attributes: FileAttributes,
+    // Excluding DOS 8.3 names, which duplicate a long name
+    names: Vec<FileName>,
+    // File size and allocated size, from the piece of the unnamed $DATA attribute starting at VCN 0
+    data_size: Option<(u64, u64)>,
+    streams: Vec<DataStream>,
+    reparse_point: Option<ReparsePoint>,
+    // Records holding this file's attributes according to its $ATTRIBUTE_LIST, when it is resident.
+    // A non-resident list can't be read from an extracted $MFT, so then extensions are trusted as is.
+    listed_records: Option<Vec<u64>>,
+}
+
+impl RecordInfo {
+    fn merge(&mut self, extension: RecordInfo) {
+        self.names.extend(extension.names);
+        self.streams.extend(extension.streams);
+        if self.data_size.is_none() {
+            self.data_size = extension.data_size;
+        }
+        if self.reparse_point.is_none() {
+            self.reparse_point = extension.reparse_point;
+        }
+    }
+
+    fn into_metadata(self, index: usize) -> FileMetadata {
+        let (file_size, allocated_size) = self.data_size.unwrap_or((0, 0));
+
+        let file_name_times = self.names.first().map(|n| n.times).unwrap_or_default();
+
+        FileMetadata {
+            index: index as u64,
+            sequence_number: self.sequence_number,
+            links: self.names.into_iter().map(|n| FileLink {
+                parent: n.parent,
+                name: n.name,
+                namespace: n.namespace,
+            }).collect(),
+            is_dir: self.is_dir,
+            file_size,
+            allocated_size,
+            streams: self.streams,
+            standard_information_times: self.standard_information_times,
+            file_name_times,
+            attributes: self.file_attributes,
+            reparse_point: self.reparse_point,
+            children: Vec::new(),
+            totals: Default::default(),
+        }
+    }
+}
+
+// Keeps only what this program needs from a file record
+pub(crate) fn parse_record(buf: &[u8]) -> Option<RecordInfo> {
+    let record = FileRecord::new(buf).ok()?;
+    if !record.is_in_use() {
+        return None;
+    }
+
+    let mut info = RecordInfo {
+        sequence_number: record.sequence_number(),
+        base_reference: record.base_reference(),
+        is_dir: record.is_directory(),
+        standard_information_times: Timestamps::default(),
+        file_attributes: FileAttributes::default(),
+        names: Vec::new(),
+        data_size: None,
+        streams: Vec::new(),
+        reparse_point: None,
+        listed_records: None,
+    };
+
+    for a in record.attributes().map_while(|attr| attr.ok()) {
+        match a.type_code() {
+            // Always in the base record
+            ATTR_STANDARD_INFORMATION => {
+                if let Some(standard_information) = a.value().ok().and_then(|v| StandardInformation::parse(v).ok()) {
+                    info.standard_information_times = standard_information.times;
+                    info.file_attributes = standard_information.file_attributes;
+                }
+            }
+            // Filename is always resident so we are fine here
+            ATTR_FILE_NAME => {
+                if let Some(file_name) = a.value().ok().and_then(|v| FileName::parse(v).ok()) {
+                    if file_name.namespace != NAMESPACE_DOS {
+                        info.names.push(file_name);
+                    }
+                }
+            }
+            // Data can be non-resident if it is too big for the MFT entry.
+            // Only the first piece of a non-resident attribute has valid sizes.
+            ATTR_DATA if a.lowest_vcn() == 0 => {
+                // When a file is compressed, allocated size is an even multiple of the compression unit size rather than the cluster size.
+                // Compressed and sparse files take up less than that, so count the clusters they actually use.
+                if a.is_unnamed() {
+                    info.data_size = Some((a.data_size(), a.on_disk_size()));
+                } else if let Ok(name) = a.name() {
+                    info.streams.push(DataStream {
+                        name,
+                        size: a.data_size(),
+                        allocated_size: a.on_disk_size(),
+                    });
+                }
+            }
+            // Reparse data is at most 16 KB and is resident in practice
+            ATTR_REPARSE_POINT => {
+                info.reparse_point = a.value().ok().and_then(|v| ReparsePoint::parse(v).ok());
+            }
+            ATTR_ATTRIBUTE_LIST if !a.is_non_resident() => {
+                if let Some(entries) = a.value().ok().and_then(|v| parse_attribute_list(v).ok()) {
+                    info.listed_records = Some(entries.iter().map(|e| e.reference.entry).collect());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(info)
+}
+
+// Records are handed to the parsing threads this many at a time
+const PARSE_BATCH_RECORDS: usize = 1024;
+
+// Consecutive records as read, which needn't be consecutive in the MFT
+struct RecordBatch {
+    indices: Vec<u64>,
+    bytes: Vec<u8>,
+}
+
+impl RecordBatch {
+    fn new(record_size: usize) -> Self {
+        RecordBatch {
+            indices: Vec::with_capacity(PARSE_BATCH_RECORDS),
+            bytes: Vec::with_capacity(PARSE_BATCH_RECORDS * record_size),
+        }
+    }
+}
+
+// Parses records on every core while `read` reads them on this thread, calling back with each record's index
+// and fixed up bytes. Each result is stored at its record's index, so the output doesn't depend on how the
+// batches were spread over the threads.
+pub(crate) fn parse_records<F>(record_count: u64, record_size: usize, progress_counter: Option<Arc<AtomicUsize>>, read: F) -> Result<Vec<Option<RecordInfo>>>
+    where
+        F: FnOnce(&mut dyn FnMut(u64, &[u8])) -> Result<()>,
+{
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    parse_records_on(threads, record_count, record_size, progress_counter, read)
+}
+
+fn parse_records_on<F>(threads: usize, record_count: u64, record_size: usize, progress_counter: Option<Arc<AtomicUsize>>, read: F) -> Result<Vec<Option<RecordInfo>>>
+    where
+        F: FnOnce(&mut dyn FnMut(u64, &[u8])) -> Result<()>,
+{
+    // Bounded, so reading can't run far ahead of parsing and buffer the whole MFT
+    let (sender, receiver) = mpsc::sync_channel::<RecordBatch>(threads * 2);
+    let receiver = Mutex::new(receiver);
+
+    let mut records = Vec::new();
+    records.resize_with(record_count as usize, || None);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(|| {
+            let mut parsed = Vec::new();
+            loop {
+                // The lock is only held while waiting for a batch, not while parsing it
+                let batch = receiver.lock().unwrap().recv();
+                // Ends once reading is done and every batch has been taken
+                let Ok(batch) = batch else { break };
+                for (index, record) in batch.indices.iter().zip(batch.bytes.chunks_exact(record_size)) {
+                    if let Some(info) = parse_record(record) {
+                        parsed.push((*index, info));
+                    }
+                }
+
+                if let Some(ref progress_counter) = progress_counter {
+                    progress_counter.fetch_add(batch.indices.len(), Ordering::Relaxed);
+                }
+            }
+            parsed
+        })).collect();
+
+        let mut batch = RecordBatch::new(record_size);
+        let mut records_read = 0;
+        let result = read(&mut |index, record| {
+            records_read += 1;
+            batch.indices.push(index);
+            batch.bytes.extend_from_slice(record);
+            if batch.indices.len() == PARSE_BATCH_RECORDS {
+                // Only fails if every worker has panicked, which joining them below reports
+                let _ = sender.send(mem::replace(&mut batch, RecordBatch::new(record_size)));
+            }
+        });
+        if !batch.indices.is_empty() {
+            let _ = sender.send(batch);
+        }
+        drop(sender);
+
+        // Records that were never written or are torn don't reach `read`'s callback, but still count as done
+        if let Some(ref progress_counter) = progress_counter {
+            progress_counter.fetch_add((record_count as usize).saturating_sub(records_read), Ordering::Relaxed);
+        }
+
+        for worker in workers {
+            for (index, info) in worker.join().unwrap() {
+                if let Some(record) = records.get_mut(index as usize) {
+                    *record = Some(info);
+                }
+            }
+        }
+
+        result
+    })?;
+
+    Ok(records)
+}
+
+// Folds every extension record into its base record, leaving only base records in the index
+pub(crate) fn merge_records(mut records: Vec<Option<RecordInfo>>) -> Vec<Option<FileMetadata>> {
+    for index in 0..records.len() {
+        let base_reference = match &records[index] {
+            Some(r) if !r.base_reference.is_null() => r.base_reference,
+            _ => continue,
+        };
+        let extension = records[index].take().unwrap();
+
+        // Extension records left behind by a file that has since been deleted or reused are dropped
+        let base = records.get_mut(base_reference.entry as usize).and_then(|b| b.as_mut());
+        if let Some(base) = base {
+            let is_listed = base.listed_records.as_ref().is_none_or(|l| l.contains(&(index as u64)));
+            if base.base_reference.is_null() && base_reference.matches(base.sequence_number) && is_listed {
+                base.merge(extension);
+            }
+        }
+    }
+
+    records.into_iter().enumerate().map(|(index, r)| r.map(|r| r.into_metadata(index))).collect()
+}
+
+// Reads one file straight from the volume, with the extension records holding the rest of its attributes.
+// None if the record isn't in use or is itself an extension record, as when scanning.
+pub(crate) fn read_file<R: VolumeSource>(reader: &mut R, layout: &MftLayout, index: u64) -> Result<Option<FileMetadata>> {
+    let mut info = match layout.read_record(reader, index).ok().and_then(|r| parse_record(&r)) {
+        Some(info) if info.base_reference.is_null() => info,
+        _ => return Ok(None),
+    };
+
+    // Unlike when scanning an extracted $MFT, a non-resident attribute list can be read
+    let attribute_list = match layout.read_attribute(reader, index, ATTR_ATTRIBUTE_LIST, "")? {
+        Some(AttributeValue::Resident(list)) => list,
+        Some(AttributeValue::NonResident(list)) => read_runs(reader, &list.runs, layout.boot_sector.cluster_size(), list.data_size)?,
+        None => Vec::new(),
+    };
+    let mut extensions: Vec<u64> = parse_attribute_list(&attribute_list)?.iter().map(|e| e.reference.entry).filter(|e| *e != index).collect();
+    extensions.sort_unstable();
+    extensions.dedup();
+
+    for extension in extensions {
+        if let Some(extension) = layout.read_record(reader, extension).ok().and_then(|r| parse_record(&r)) {
+            if extension.base_reference.entry == index && extension.base_reference.matches(info.sequence_number) {
+                info.merge(extension);
+            }
+        }
+    }
+
+    Ok(Some(info.into_metadata(index as usize)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{apply_fixups, NAMESPACE_WIN32};
+    use crate::testing::*;
+
+    fn parsed(mut record: Vec<u8>) -> Option<RecordInfo> {
+        apply_fixups(&mut record).unwrap();
+        parse_record(&record)
+    }
+
+    fn names(file: &FileMetadata) -> Vec<&str> {
+        file.links.iter().map(|l| l.name.as_str()).collect()
+    }
+
+    #[test]
+    fn extensions_merge_into_their_base() {
+        let base = record(3, IN_USE, FileReference::default(), &[
+            resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), "first", NAMESPACE_WIN32)),
+            resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), "FIRST~1", NAMESPACE_DOS)),
+        ]);
+        let extension = record(1, IN_USE, reference(2, 3), &[
+            resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), "second", NAMESPACE_WIN32)),
+            non_resident(&NonResident::new(ATTR_DATA, "", vec![(2, Some(40))], 5000)),
+            resident(ATTR_DATA, "ads", b"stream"),
+        ]);
+        let records = vec![None, Some(parsed(extension).unwrap()), Some(parsed(base).unwrap())];
+
+        let files = merge_records(records);
+        assert!(files[0].is_none() && files[1].is_none());
+        let file = files[2].as_ref().unwrap();
+        assert_eq!((file.index, file.sequence_number), (2, 3));
+        assert_eq!(names(file), ["first", "second"]);
+        assert_eq!((file.file_size, file.allocated_size), (5000, 8192));
+        assert_eq!((file.streams[0].name.as_str(), file.streams[0].size), ("ads", 6));
+    }
+
+    #[test]
+    fn stale_extensions_are_dropped() {
+        let name = |n| resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), n, NAMESPACE_WIN32));
+        // Record 1 lists only record 2 as an extension
+        let list = attribute_list_entry(ATTR_FILE_NAME, "", 0, reference(2, 1));
+        let records = vec![
+            // Its base has been reused since
+            parsed(record(1, IN_USE, reference(1, 4), &[name("old sequence")])),
+            parsed(record(5, IN_USE, FileReference::default(), &[resident(ATTR_ATTRIBUTE_LIST, "", &list), name("base")])),
+            parsed(record(1, IN_USE, reference(1, 5), &[name("listed")])),
+            parsed(record(1, IN_USE, reference(1, 5), &[name("unlisted")])),
+            // Or deleted, or past the end of the MFT
+            parsed(record(1, IN_USE, reference(6, 1), &[name("no base")])),
+            parsed(record(1, IN_USE, reference(99, 1), &[name("out of range")])),
+            None,
+            parsed(record(1, 0, FileReference::default(), &[name("deleted")])),
+        ];
+        assert!(records[7].is_none());
+
+        let files = merge_records(records);
+        assert_eq!(names(files[1].as_ref().unwrap()), ["base", "listed"]);
+        assert_eq!(files.iter().filter(|f| f.is_some()).count(), 1);
+    }
+
+    #[test]
+    fn times_and_attributes() {
+        let file = record(1, IN_USE, FileReference::default(), &[
+            resident(ATTR_STANDARD_INFORMATION, "", &standard_information([1, 2, 3, 4], FileAttributes::HIDDEN | FileAttributes::ARCHIVE)),
+            resident(ATTR_FILE_NAME, "", &file_name_with_times(reference(5, 5), "a", NAMESPACE_WIN32, [5, 6, 7, 8])),
+        ]);
+        let file = parsed(file).unwrap().into_metadata(1);
+
+        let times = file.standard_information_times;
+        assert_eq!([times.created, times.modified, times.mft_modified, times.accessed].map(|t| t.0), [1, 2, 3, 4]);
+        let times = file.file_name_times;
+        assert_eq!([times.created, times.modified, times.mft_modified, times.accessed].map(|t| t.0), [5, 6, 7, 8]);
+        assert!(file.attributes.is_hidden() && !file.attributes.is_system());
+    }
+
+    #[test]
+    fn truncated_standard_information() {
+        assert!(StandardInformation::parse(&[0; 0x2F]).is_err());
+        let file = record(1, IN_USE, FileReference::default(), &[resident(ATTR_STANDARD_INFORMATION, "", &[0xFF; 0x20])]);
+        assert_eq!(parsed(file).unwrap().into_metadata(1).standard_information_times, Timestamps::default());
+    }
+
+    #[test]
+    fn same_records_on_any_number_of_threads() {
+        // Enough for several batches, read backwards, with every third record never written
+        let record_count = 3 * PARSE_BATCH_RECORDS as u64 + 5;
+        let records: Vec<_> = (0..record_count).filter(|i| i % 3 != 0).map(|i| {
+            let name = resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), &format!("file {}", i), NAMESPACE_WIN32));
+            let mut r = record(i as u16, IN_USE, FileReference::default(), &[name]);
+            apply_fixups(&mut r).unwrap();
+            (i, r)
+        }).collect();
+
+        let parse_on = |threads| {
+            let progress = Arc::new(AtomicUsize::new(0));
+            let parsed = parse_records_on(threads, record_count, RECORD_SIZE, Some(progress.clone()), |f| {
+                for (index, record) in records.iter().rev() {
+                    f(*index, record);
+                }
+                Ok(())
+            }).unwrap();
+            assert_eq!(progress.load(Ordering::Relaxed), record_count as usize);
+
+            parsed.into_iter().enumerate().map(|(i, r)| r.map(|r| describe(&r.into_metadata(i)))).collect::<Vec<_>>()
+        };
+
+        let files = parse_on(1);
+        assert_eq!(files.iter().filter(|f| f.is_some()).count(), records.len());
+        assert!(files[3].is_none() && files[4].as_ref().unwrap().contains("file 4"));
+        for threads in [2, 7] {
+            assert_eq!(parse_on(threads), files);
+        }
+    }
+}
+
@@ -0,0 +1,479 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use anyhow::{bail, Result};
+use phf::phf_map;
+use crate::bytes::{le_u16, le_u32, le_u64};
+use crate::verify_ntfs_system_id;
+use crate::volume::{CacheStats, VolumeSource};
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+// Guards against looping forever on a corrupt EBR chain
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+static MBR_PARTITION_TYPES: phf::Map<u8, &'static str> = phf_map! {
+    0x01u8 => "FAT12",
+    0x04u8 => "FAT16",
+    0x05u8 => "Extended",
+    0x06u8 => "FAT16",
+    0x07u8 => "NTFS/exFAT",
+    0x0Bu8 => "FAT32",
+    0x0Cu8 => "FAT32 (LBA)",
+    0x0Eu8 => "FAT16 (LBA)",
+    0x0Fu8 => "Extended (LBA)",
+    0x27u8 => "Windows Recovery",
+    0x42u8 => "Windows Dynamic Disk",
+    0x82u8 => "Linux Swap",
+    0x83u8 => "Linux",
+    0x85u8 => "Linux Extended",
+    0x8Eu8 => "Linux LVM",
+    0xEEu8 => "GPT Protective",
+    0xEFu8 => "EFI System",
+};
+
+static GPT_PARTITION_TYPES: phf::Map<&'static str, &'static str> = phf_map! {
+    "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Basic Data",
+    "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+    "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft Reserved",
+    "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows Recovery",
+    "5808C8AA-7E8F-42E0-85D2-E1E90434CFB3" => "LDM Metadata",
+    "AF9B60A0-1431-4F62-BC68-3311714A69AD" => "LDM Data",
+    "E75CAF8F-F680-4CEE-AFA3-B001E56EFC2D" => "Storage Spaces",
+    "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux Filesystem",
+    "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux Swap",
+    "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    // Type GUID in its canonical string form
+    Gpt(String),
+}
+
+impl PartitionType {
+    pub fn description(&self) -> String {
+        match self {
+            PartitionType::Mbr(t) => match MBR_PARTITION_TYPES.get(t) {
+                Some(name) => format!("{} (0x{:02X})", name, t),
+                None => format!("Unknown (0x{:02X})", t),
+            },
+            PartitionType::Gpt(guid) => match GPT_PARTITION_TYPES.get(guid.as_str()) {
+                Some(name) => name.to_string(),
+                None => guid.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    // 1-based, in table order. For MBR, logical partitions are numbered from 5 like Linux does
+    pub number: u32,
+    pub partition_type: PartitionType,
+    // GPT partition label, if any
+    pub name: Option<String>,
+    // Byte offset and length within the disk
+    pub offset: u64,
+    pub length: u64,
+    pub is_ntfs: bool,
+}
+
+// Lists the partitions of a whole disk from its GPT, or from its MBR including logical partitions.
+// Returns an empty list if the disk has no partition table.
+pub fn read_partitions<R: VolumeSource>(disk: &mut R) -> Result<Vec<Partition>> {
+    let sector_size = disk.sector_size() as u64;
+
+    let mut mbr = vec![0u8; sector_size.max(512) as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut mbr)?;
+    if le_u16(&mbr, 510) != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = if (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_GPT_PROTECTIVE) {
+        read_gpt_partitions(disk, sector_size)?
+    } else {
+        read_mbr_partitions(disk, &mbr, sector_size)?
+    };
+
+    for p in &mut partitions {
+        p.is_ntfs = verify_ntfs_system_id(&mut PartitionReader::new(&mut *disk, p.offset, p.length));
+    }
+
+    Ok(partitions)
+}
+
+fn read_mbr_partitions<R: VolumeSource>(disk: &mut R, mbr: &[u8], sector_size: u64) -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended_start = None;
+
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let partition_type = entry[4];
+        let start_lba = le_u32(entry, 8) as u64;
+        let sector_count = le_u32(entry, 12) as u64;
+        if partition_type == 0 || sector_count == 0 {
+            continue;
+        }
+
+        if MBR_EXTENDED_TYPES.contains(&partition_type) {
+            extended_start = Some(start_lba);
+        } else {
+            partitions.push(Partition {
+                number: i as u32 + 1,
+                partition_type: PartitionType::Mbr(partition_type),
+                name: None,
+                offset: start_lba * sector_size,
+                length: sector_count * sector_size,
+                is_ntfs: false,
+            });
+        }
+    }
+
+    // Logical partitions are a chain of EBRs. Each EBR's first entry is relative to the EBR itself,
+    // and its second entry points to the next EBR relative to the start of the extended partition.
+    if let Some(extended_start) = extended_start {
+        let mut ebr = vec![0u8; mbr.len()];
+        let mut ebr_lba = extended_start;
+
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS as u32 {
+            // A link that points past the end of the disk ends the chain, keeping the partitions found so far
+            let read = match ebr_lba.checked_mul(sector_size) {
+                Some(offset) => disk.seek(SeekFrom::Start(offset)).and_then(|_| disk.read_exact(&mut ebr)),
+                None => break,
+            };
+            if read.is_err() || le_u16(&ebr, 510) != MBR_SIGNATURE {
+                break;
+            }
+
+            let logical = &ebr[446..462];
+            if logical[4] != 0 && le_u32(logical, 12) != 0 {
+                partitions.push(Partition {
+                    number,
+                    partition_type: PartitionType::Mbr(logical[4]),
+                    name: None,
+                    offset: (ebr_lba + le_u32(logical, 8) as u64) * sector_size,
+                    length: le_u32(logical, 12) as u64 * sector_size,
+                    is_ntfs: false,
+                });
+            }
+
+            let next = &ebr[462..478];
+            if next[4] == 0 || le_u32(next, 8) == 0 {
+                break;
+            }
+            ebr_lba = extended_start + le_u32(next, 8) as u64;
+        }
+    }
+
+    Ok(partitions)
+}
+
+fn read_gpt_partitions<R: VolumeSource>(disk: &mut R, sector_size: u64) -> Result<Vec<Partition>> {
+    let mut header = vec![0u8; sector_size.max(512) as usize];
+    disk.seek(SeekFrom::Start(sector_size))?;
+    disk.read_exact(&mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        bail!("Disk has a protective MBR but no GPT header");
+    }
+
+    let entries_lba = le_u64(&header, 72);
+    let entry_count = le_u32(&header, 80) as usize;
+    let entry_size = le_u32(&header, 84) as usize;
+    if !(128..=4096).contains(&entry_size) || !entry_size.is_multiple_of(128) || entry_count > 1024 {
+        bail!("GPT header has an invalid partition entry array");
+    }
+    let (Some(entries_length), Some(entries_offset)) = (entry_count.checked_mul(entry_size), entries_lba.checked_mul(sector_size)) else {
+        bail!("GPT header has an invalid partition entry array");
+    };
+
+    let mut entries = vec![0u8; entries_length];
+    disk.seek(SeekFrom::Start(entries_offset))?;
+    disk.read_exact(&mut entries)?;
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let entry = &entries[i * entry_size..(i + 1) * entry_size];
+        let type_guid = &entry[0..16];
+        if type_guid.iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let first_lba = le_u64(entry, 32);
+        let last_lba = le_u64(entry, 40);
+        // Entries whose offset doesn't fit in 64 bits can't be on the disk
+        let Some(offset) = first_lba.checked_mul(sector_size) else {
+            continue;
+        };
+        let name: Vec<u16> = (0..36).map(|c| le_u16(entry, 56 + c * 2)).take_while(|c| *c != 0).collect();
+        let name = String::from_utf16_lossy(&name);
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            partition_type: PartitionType::Gpt(format_guid(type_guid)),
+            name: if name.is_empty() { None } else { Some(name) },
+            offset,
+            length: last_lba.saturating_add(1).saturating_sub(first_lba).saturating_mul(sector_size),
+            is_ntfs: false,
+        });
+    }
+
+    Ok(partitions)
+}
+
+// Formats a GUID stored in its mixed-endian on-disk layout
+fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        le_u32(guid, 0),
+        le_u16(guid, 4),
+        le_u16(guid, 6),
+        guid[8],
+        guid[9],
+        guid[10..16].iter().map(|b| format!("{:02X}", b)).collect::<String>(),
+    )
+}
+
+// A window onto one partition of a disk, addressed from the start of the partition
+pub struct PartitionReader<R: VolumeSource> {
+    pub disk: R,
+    pub offset: u64,
+    pub length: u64,
+    pub position: u64,
+}
+
+impl<R: VolumeSource> PartitionReader<R> {
+    pub fn new(disk: R, offset: u64, length: u64) -> Self {
+        PartitionReader {
+            disk,
+            offset,
+            length,
+            position: 0,
+        }
+    }
+
+    pub fn from_partition(disk: R, partition: &Partition) -> Self {
+        Self::new(disk, partition.offset, partition.length)
+    }
+}
+
+impl<R: VolumeSource> Read for PartitionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.length.saturating_sub(self.position) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.disk.seek(SeekFrom::Start(self.offset + self.position))?;
+        let n = self.disk.read(&mut buf[..len])?;
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: VolumeSource> Seek for PartitionReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match new_position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")),
+        }
+    }
+}
+
+impl<R: VolumeSource> VolumeSource for PartitionReader<R> {
+    fn sector_size(&self) -> u32 {
+        self.disk.sector_size()
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_ahead(&mut self, extents: &[Range<u64>]) {
+        let extents: Vec<Range<u64>> = extents.iter().map(|e| self.offset + e.start..self.offset + e.end).collect();
+        self.disk.read_ahead(&extents)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.disk.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SECTOR: usize = 512;
+
+    fn mbr_entry(sector: &mut [u8], i: usize, partition_type: u8, start_lba: u32, sector_count: u32) {
+        let entry = &mut sector[446 + i * 16..446 + (i + 1) * 16];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&start_lba.to_le_bytes());
+        entry[12..16].copy_from_slice(&sector_count.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+    }
+
+    fn put_ntfs_boot_sector(disk: &mut [u8], lba: usize) {
+        disk[lba * SECTOR + 3..lba * SECTOR + 11].copy_from_slice(b"NTFS    ");
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut disk = vec![0u8; 64 * SECTOR];
+        mbr_entry(&mut disk[..SECTOR], 0, 0x07, 4, 8);
+        mbr_entry(&mut disk[..SECTOR], 1, 0x05, 16, 32);
+        put_ntfs_boot_sector(&mut disk, 4);
+        // Each EBR's partition is relative to the EBR, the next EBR to the extended partition
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 0, 0x83, 1, 4);
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 1, 0x05, 8, 8);
+        mbr_entry(&mut disk[24 * SECTOR..25 * SECTOR], 0, 0x07, 2, 4);
+        put_ntfs_boot_sector(&mut disk, 26);
+
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        let found: Vec<_> = partitions.iter().map(|p| (p.number, p.offset, p.length, p.is_ntfs)).collect();
+        assert_eq!(found, [
+            (1, 4 * SECTOR as u64, 8 * SECTOR as u64, true),
+            (5, 17 * SECTOR as u64, 4 * SECTOR as u64, false),
+            (6, 26 * SECTOR as u64, 4 * SECTOR as u64, true),
+        ]);
+        assert_eq!(partitions[0].partition_type.description(), "NTFS/exFAT (0x07)");
+        assert_eq!(partitions[1].partition_type, PartitionType::Mbr(0x83));
+    }
+
+    #[test]
+    fn ebr_loop_terminates() {
+        let mut disk = vec![0u8; 64 * SECTOR];
+        mbr_entry(&mut disk[..SECTOR], 0, 0x0F, 16, 32);
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 0, 0x07, 1, 1);
+        mbr_entry(&mut disk[24 * SECTOR..25 * SECTOR], 0, 0x07, 1, 1);
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 1, 0x05, 8, 8);
+        // The second EBR points at itself
+        mbr_entry(&mut disk[24 * SECTOR..25 * SECTOR], 1, 0x05, 8, 8);
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        assert_eq!(partitions.len(), MAX_LOGICAL_PARTITIONS);
+    }
+
+    #[test]
+    fn ebr_past_end_of_disk() {
+        let mut disk = vec![0u8; 64 * SECTOR];
+        mbr_entry(&mut disk[..SECTOR], 0, 0x0F, 16, 32);
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 0, 0x07, 1, 4);
+        // The next EBR would be far beyond the end of the disk
+        mbr_entry(&mut disk[16 * SECTOR..17 * SECTOR], 1, 0x05, 1 << 20, 8);
+
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        let found: Vec<_> = partitions.iter().map(|p| (p.number, p.offset)).collect();
+        assert_eq!(found, [(5, 17 * SECTOR as u64)]);
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let disk = vec![0u8; 4 * SECTOR];
+        assert!(read_partitions(&mut Cursor::new(disk)).unwrap().is_empty());
+    }
+
+    const BASIC_DATA: [u8; 16] = [0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7];
+
+    fn gpt_disk() -> Vec<u8> {
+        let mut disk = vec![0u8; 64 * SECTOR];
+        mbr_entry(&mut disk[..SECTOR], 0, MBR_GPT_PROTECTIVE, 1, 63);
+
+        let header = &mut disk[SECTOR..2 * SECTOR];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+        let entries = &mut disk[2 * SECTOR..];
+        entries[0..16].copy_from_slice(&BASIC_DATA);
+        entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&41u64.to_le_bytes());
+        for (i, c) in "Data".encode_utf16().enumerate() {
+            entries[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        // The second entry is unused, the third has an unknown type
+        entries[256] = 0x01;
+        entries[256 + 32..256 + 40].copy_from_slice(&50u64.to_le_bytes());
+        entries[256 + 40..256 + 48].copy_from_slice(&59u64.to_le_bytes());
+
+        put_ntfs_boot_sector(&mut disk, 34);
+        disk
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let partitions = read_partitions(&mut Cursor::new(gpt_disk())).unwrap();
+        assert_eq!(partitions.len(), 2);
+
+        let p = &partitions[0];
+        assert_eq!((p.number, p.offset, p.length, p.is_ntfs), (1, 34 * SECTOR as u64, 8 * SECTOR as u64, true));
+        assert_eq!(p.name.as_deref(), Some("Data"));
+        assert_eq!(p.partition_type.description(), "Basic Data");
+
+        let p = &partitions[1];
+        assert_eq!((p.number, p.offset, p.name.as_ref()), (3, 50 * SECTOR as u64, None));
+        assert_eq!(p.partition_type.description(), "00000001-0000-0000-0000-000000000000");
+    }
+
+    #[test]
+    fn damaged_gpt() {
+        let mut disk = gpt_disk();
+        disk[SECTOR] = 0;
+        assert!(read_partitions(&mut Cursor::new(disk)).is_err());
+
+        let mut disk = gpt_disk();
+        disk[SECTOR + 84..SECTOR + 88].copy_from_slice(&16u32.to_le_bytes());
+        assert!(read_partitions(&mut Cursor::new(disk)).is_err());
+
+        // Entry sizes must be a multiple of 128 bytes, up to 4096
+        for entry_size in [200u32, 8192, !127] {
+            let mut disk = gpt_disk();
+            disk[SECTOR + 84..SECTOR + 88].copy_from_slice(&entry_size.to_le_bytes());
+            assert!(read_partitions(&mut Cursor::new(disk)).is_err());
+        }
+
+        let mut disk = gpt_disk();
+        disk[SECTOR + 72..SECTOR + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_partitions(&mut Cursor::new(disk)).is_err());
+    }
+
+    #[test]
+    fn gpt_entry_offset_overflow() {
+        let mut disk = gpt_disk();
+        disk[2 * SECTOR + 32..2 * SECTOR + 40].copy_from_slice(&(u64::MAX / 4).to_le_bytes());
+        let partitions = read_partitions(&mut Cursor::new(disk)).unwrap();
+        assert_eq!(partitions.iter().map(|p| p.number).collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn partition_reader_window() {
+        let disk: Vec<u8> = (0..4096).map(|i| (i % 256) as u8).collect();
+        let mut reader = PartitionReader::new(Cursor::new(disk), 1024, 1000);
+        assert_eq!(reader.length(), 1000);
+
+        let mut buf = [0u8; 4];
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [10, 11, 12, 13]);
+
+        // Reads stop at the end of the partition, not the disk
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use anyhow::{bail, Result};
+use crate::bytes::{le_u16, le_u32, le_u64};
+use crate::time::{FileTime, Timestamps};
+
+// Update sequence arrays protect every 512 bytes of a record, whatever the sector size
+pub const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+pub const ATTR_STANDARD_INFORMATION: u32 = 0x10;
+pub const ATTR_ATTRIBUTE_LIST: u32 = 0x20;
+pub const ATTR_FILE_NAME: u32 = 0x30;
+pub const ATTR_DATA: u32 = 0x80;
+pub const ATTR_REPARSE_POINT: u32 = 0xC0;
+const ATTR_END: u32 = 0xFFFF_FFFF;
+
+const ATTR_FLAG_COMPRESSED: u16 = 0x0001;
+const ATTR_FLAG_ENCRYPTED: u16 = 0x4000;
+const ATTR_FLAG_SPARSE: u16 = 0x8000;
+
+const RECORD_IN_USE: u16 = 0x0001;
+const RECORD_IS_DIRECTORY: u16 = 0x0002;
+
+pub const NAMESPACE_POSIX: u8 = 0;
+pub const NAMESPACE_WIN32: u8 = 1;
+pub const NAMESPACE_DOS: u8 = 2;
+pub const NAMESPACE_WIN32_AND_DOS: u8 = 3;
+
+// File references pack a 48 bit record number with a 16 bit sequence number, which NTFS bumps every time
+// the record is freed. A reference whose sequence number doesn't match is to a file that no longer exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FileReference {
+    pub entry: u64,
+    pub sequence: u16,
+}
+
+impl FileReference {
+    pub fn from_u64(reference: u64) -> Self {
+        FileReference {
+            entry: reference & 0x0000_FFFF_FFFF_FFFF,
+            sequence: (reference >> 48) as u16,
+        }
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        self.entry | (self.sequence as u64) << 48
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.entry == 0 && self.sequence == 0
+    }
+
+    // Sequence number 0 means unchecked, as used by some system files
+    pub fn matches(&self, sequence_number: u16) -> bool {
+        self.sequence == 0 || self.sequence == sequence_number
+    }
+}
+
+// Restores the last two bytes of every stride from the update sequence array, checking each stride still ends
+// with the update sequence number. A mismatch means the record was torn by an interrupted write.
+pub fn apply_fixups(buf: &mut [u8]) -> Result<()> {
+    if buf.len() < 8 {
+        bail!("Record is too short to have an update sequence array");
+    }
+
+    let usa_offset = le_u16(buf, 4) as usize;
+    let usa_count = le_u16(buf, 6) as usize;
+    // The first entry is the update sequence number itself, then one entry per stride
+    if usa_count < 2 || usa_offset + usa_count * 2 > buf.len() || (usa_count - 1) * UPDATE_SEQUENCE_STRIDE > buf.len() {
+        bail!("Record has an invalid update sequence array");
+    }
+
+    for i in 1..usa_count {
+        let end = i * UPDATE_SEQUENCE_STRIDE;
+        if buf[end - 2..end] != buf[usa_offset..usa_offset + 2] {
+            bail!("Record failed its update sequence check in stride {}", i - 1);
+        }
+        buf.copy_within(usa_offset + i * 2..usa_offset + i * 2 + 2, end - 2);
+    }
+
+    Ok(())
+}
+
+// A view of one MFT file record, after its fixups have been applied
+pub struct FileRecord<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> FileRecord<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        if buf.len() < 0x30 || &buf[0..4] != b"FILE" {
+            bail!("Not an MFT file record");
+        }
+        if le_u16(buf, 0x14) as usize >= buf.len() {
+            bail!("File record's first attribute is out of bounds");
+        }
+
+        Ok(FileRecord { buf })
+    }
+
+    pub fn sequence_number(&self) -> u16 {
+        le_u16(self.buf, 0x10)
+    }
+
+    pub fn is_in_use(&self) -> bool {
+        le_u16(self.buf, 0x16) & RECORD_IN_USE != 0
+    }
+
+    // Set when the record has a $I30 index, i.e. it is a directory
+    pub fn is_directory(&self) -> bool {
+        le_u16(self.buf, 0x16) & RECORD_IS_DIRECTORY != 0
+    }
+
+    // The base record this extension record belongs to, or null for base records
+    pub fn base_reference(&self) -> FileReference {
+        FileReference::from_u64(le_u64(self.buf, 0x20))
+    }
+
+    pub fn attributes(&self) -> Attributes<'a> {
+        // Attributes stop at the used size of the record, not the allocated size
+        let used = (le_u32(self.buf, 0x18) as usize).min(self.buf.len());
+        Attributes {
+            buf: &self.buf[..used],
+            offset: le_u16(self.buf, 0x14) as usize,
+        }
+    }
+}
+
+pub struct Attributes<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = Result<Attribute<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + 4 > self.buf.len() || le_u32(self.buf, self.offset) == ATTR_END {
+            return None;
+        }
+
+        let start = self.offset;
+        // Stop after an error, the rest of the record can't be trusted
+        self.offset = self.buf.len();
+        if start + 16 > self.buf.len() {
+            return Some(Err(anyhow::anyhow!("Attribute header is truncated")));
+        }
+
+        let length = le_u32(self.buf, start + 4) as usize;
+        let non_resident = self.buf[start + 8] != 0;
+        let min_length = if non_resident { 64 } else { 24 };
+        if length < min_length || start + length > self.buf.len() {
+            return Some(Err(anyhow::anyhow!("Attribute has an invalid length")));
+        }
+
+        self.offset = start + length;
+        Some(Ok(Attribute { buf: &self.buf[start..start + length] }))
+    }
+}
+
+pub struct Attribute<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Attribute<'a> {
+    pub fn type_code(&self) -> u32 {
+        le_u32(self.buf, 0)
+    }
+
+    pub fn is_non_resident(&self) -> bool {
+        self.buf[8] != 0
+    }
+
+    pub fn name(&self) -> Result<String> {
+        let length = self.buf[9] as usize;
+        let offset = le_u16(self.buf, 10) as usize;
+        if offset + length * 2 > self.buf.len() {
+            bail!("Attribute name is out of bounds");
+        }
+
+        Ok(utf16_le(&self.buf[offset..offset + length * 2]))
+    }
+
+    pub fn is_unnamed(&self) -> bool {
+        self.buf[9] == 0
+    }
+
+    pub fn flags(&self) -> u16 {
+        le_u16(self.buf, 12)
+    }
+
+    // The value of a resident attribute
+    pub fn value(&self) -> Result<&'a [u8]> {
+        if self.is_non_resident() {
+            bail!("Attribute is non-resident");
+        }
+
+        let length = le_u32(self.buf, 16) as usize;
+        let offset = le_u16(self.buf, 20) as usize;
+        if offset + length > self.buf.len() {
+            bail!("Resident attribute value is out of bounds");
+        }
+
+        Ok(&self.buf[offset..offset + length])
+    }
+
+    // The first VCN this piece of a non-resident attribute maps. Only the piece starting at VCN 0 has valid sizes.
+    pub fn lowest_vcn(&self) -> u64 {
+        if self.is_non_resident() { le_u64(self.buf, 16) } else { 0 }
+    }
+
+    pub fn data_size(&self) -> u64 {
+        if self.is_non_resident() { le_u64(self.buf, 48) } else { le_u32(self.buf, 16) as u64 }
+    }
+
+    // Bytes actually written. Past this, up to the data size, reads as zeros whatever the clusters hold.
+    pub fn initialized_size(&self) -> u64 {
+        if self.is_non_resident() { le_u64(self.buf, 56) } else { le_u32(self.buf, 16) as u64 }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags() & ATTR_FLAG_COMPRESSED != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags() & ATTR_FLAG_ENCRYPTED != 0
+    }
+
+    // Log2 of the compression unit size in clusters, 0 when the attribute isn't compressed
+    pub fn compression_unit(&self) -> u8 {
+        if self.is_non_resident() { self.buf[0x22] } else { 0 }
+    }
+
+    // Rounded up to whole clusters, or to whole compression units for compressed attributes
+    pub fn allocated_size(&self) -> u64 {
+        if self.is_non_resident() { le_u64(self.buf, 40) } else { le_u32(self.buf, 16) as u64 }
+    }
+
+    // Clusters actually in use, which for compressed and sparse attributes is less than the allocated size
+    pub fn on_disk_size(&self) -> u64 {
+        let has_total_allocated = self.flags() & (ATTR_FLAG_COMPRESSED | ATTR_FLAG_SPARSE) != 0;
+        if self.is_non_resident() && has_total_allocated && self.buf.len() >= 72 {
+            le_u64(self.buf, 64)
+        } else {
+            self.allocated_size()
+        }
+    }
+
+    pub fn data_runs(&self) -> Result<Vec<DataRun>> {
+        if !self.is_non_resident() {
+            bail!("Attribute is resident and has no data runs");
+        }
+
+        let offset = le_u16(self.buf, 32) as usize;
+        if offset > self.buf.len() {
+            bail!("Data runs are out of bounds");
+        }
+
+        decode_data_runs(&self.buf[offset..])
+    }
+}
+
+// A contiguous run of clusters. Sparse runs have no LCN and read as zeros.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataRun {
+    pub lcn: Option<u64>,
+    pub length: u64,
+}
+
+// Each run starts with a header byte giving the sizes of its length and LCN fields. LCNs are stored as
+// signed deltas from the previous run's LCN, and a run with no LCN field is sparse.
+pub fn decode_data_runs(buf: &[u8]) -> Result<Vec<DataRun>> {
+    let mut runs = Vec::new();
+    let mut position = 0;
+    let mut lcn = 0i64;
+
+    while position < buf.len() && buf[position] != 0 {
+        let length_size = (buf[position] & 0x0F) as usize;
+        let offset_size = (buf[position] >> 4) as usize;
+        position += 1;
+        if length_size == 0 || length_size > 8 || offset_size > 8 || position + length_size + offset_size > buf.len() {
+            bail!("Data run header is invalid");
+        }
+
+        let length = le_uint(&buf[position..position + length_size]);
+        position += length_size;
+
+        let run_lcn = if offset_size == 0 {
+            None
+        } else {
+            let delta = le_int(&buf[position..position + offset_size]);
+            position += offset_size;
+            lcn = match lcn.checked_add(delta) {
+                Some(l) if l >= 0 => l,
+                _ => bail!("Data run points before the start of the volume"),
+            };
+            Some(lcn as u64)
+        };
+
+        runs.push(DataRun { lcn: run_lcn, length });
+    }
+
+    Ok(runs)
+}
+
+pub struct FileName {
+    pub parent: FileReference,
+    pub times: Timestamps,
+    pub namespace: u8,
+    pub name: String,
+}
+
+impl FileName {
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        if value.len() < 0x42 {
+            bail!("$FILE_NAME is truncated");
+        }
+
+        let length = value[0x40] as usize;
+        if 0x42 + length * 2 > value.len() {
+            bail!("$FILE_NAME name is out of bounds");
+        }
+
+        Ok(FileName {
+            parent: FileReference::from_u64(le_u64(value, 0)),
+            times: read_timestamps(value, 8),
+            namespace: value[0x41],
+            name: utf16_le(&value[0x42..0x42 + length * 2]),
+        })
+    }
+}
+
+// The FILE_ATTRIBUTE_* bits Windows reports for a file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FileAttributes(pub u32);
+
+impl FileAttributes {
+    pub const READ_ONLY: u32 = 0x0000_0001;
+    pub const HIDDEN: u32 = 0x0000_0002;
+    pub const SYSTEM: u32 = 0x0000_0004;
+    pub const ARCHIVE: u32 = 0x0000_0020;
+    pub const SPARSE_FILE: u32 = 0x0000_0200;
+    pub const REPARSE_POINT: u32 = 0x0000_0400;
+    pub const COMPRESSED: u32 = 0x0000_0800;
+    pub const OFFLINE: u32 = 0x0000_1000;
+    pub const NOT_CONTENT_INDEXED: u32 = 0x0000_2000;
+    pub const ENCRYPTED: u32 = 0x0000_4000;
+    pub const RECALL_ON_OPEN: u32 = 0x0004_0000;
+    pub const RECALL_ON_DATA_ACCESS: u32 = 0x0040_0000;
+
+    pub fn contains(&self, flags: u32) -> bool {
+        self.0 & flags == flags
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.contains(Self::READ_ONLY)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.contains(Self::HIDDEN)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.contains(Self::SYSTEM)
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.contains(Self::SPARSE_FILE)
+    }
+
+    pub fn is_reparse_point(&self) -> bool {
+        self.contains(Self::REPARSE_POINT)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.contains(Self::COMPRESSED)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.contains(Self::ENCRYPTED)
+    }
+
+    // Set on files whose contents aren't fully local, and would be fetched by opening or reading them
+    pub fn is_recall_on_access(&self) -> bool {
+        self.0 & (Self::RECALL_ON_OPEN | Self::RECALL_ON_DATA_ACCESS) != 0
+    }
+}
+
+pub struct StandardInformation {
+    pub times: Timestamps,
+    pub file_attributes: FileAttributes,
+}
+
+impl StandardInformation {
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        // Only the NTFS 1.2 fields, which NTFS 3.x keeps at the same offsets
+        if value.len() < 0x30 {
+            bail!("$STANDARD_INFORMATION is truncated");
+        }
+
+        Ok(StandardInformation {
+            times: read_timestamps(value, 0),
+            file_attributes: FileAttributes(le_u32(value, 0x20)),
+        })
+    }
+}
+
+fn read_timestamps(buf: &[u8], offset: usize) -> Timestamps {
+    Timestamps {
+        created: FileTime(le_u64(buf, offset)),
+        modified: FileTime(le_u64(buf, offset + 8)),
+        mft_modified: FileTime(le_u64(buf, offset + 16)),
+        accessed: FileTime(le_u64(buf, offset + 24)),
+    }
+}
+
+pub struct AttributeListEntry {
+    pub type_code: u32,
+    pub name: String,
+    pub lowest_vcn: u64,
+    // The record holding the attribute, which may be the base record itself
+    pub reference: FileReference,
+}
+
+pub fn parse_attribute_list(buf: &[u8]) -> Result<Vec<AttributeListEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + 26 <= buf.len() {
+        let length = le_u16(buf, offset + 4) as usize;
+        let name_length = buf[offset + 6] as usize;
+        let name_offset = buf[offset + 7] as usize;
+        if length < 26 || offset + length > buf.len() || name_offset + name_length * 2 > length {
+            bail!("Attribute list entry has an invalid length");
+        }
+
+        let entry = &buf[offset..offset + length];
+        entries.push(AttributeListEntry {
+            type_code: le_u32(entry, 0),
+            name: utf16_le(&entry[name_offset..name_offset + name_length * 2]),
+            lowest_vcn: le_u64(entry, 8),
+            reference: FileReference::from_u64(le_u64(entry, 16)),
+        });
+        offset += length;
+    }
+
+    Ok(entries)
+}
+
+fn utf16_le(buf: &[u8]) -> String {
+    let units: Vec<u16> = buf.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn le_uint(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |value, b| value << 8 | *b as u64)
+}
+
+fn le_int(buf: &[u8]) -> i64 {
+    // Sign extend from the most significant byte
+    let shift = 64 - buf.len() * 8;
+    ((le_uint(buf) << shift) as i64) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn fixups_restore_stride_ends() {
+        let mut r = record(1, IN_USE, FileReference::default(), &[]);
+        // What the last two bytes of each stride held before they were swapped out
+        r[0x32..0x34].copy_from_slice(&[0xAB, 0xCD]);
+        r[0x34..0x36].copy_from_slice(&[0xEF, 0x01]);
+        assert_eq!(r[510..512], [0x34, 0x12]);
+
+        apply_fixups(&mut r).unwrap();
+        assert_eq!(r[510..512], [0xAB, 0xCD]);
+        assert_eq!(r[1022..1024], [0xEF, 0x01]);
+    }
+
+    #[test]
+    fn torn_and_invalid_records() {
+        let mut torn = record(1, IN_USE, FileReference::default(), &[]);
+        torn[1022] = 0;
+        assert!(apply_fixups(&mut torn).is_err());
+
+        let mut too_many_strides = record(1, IN_USE, FileReference::default(), &[]);
+        too_many_strides[6..8].copy_from_slice(&4u16.to_le_bytes());
+        assert!(apply_fixups(&mut too_many_strides).is_err());
+
+        let mut out_of_bounds = record(1, IN_USE, FileReference::default(), &[]);
+        out_of_bounds[4..6].copy_from_slice(&1020u16.to_le_bytes());
+        assert!(apply_fixups(&mut out_of_bounds).is_err());
+
+        assert!(apply_fixups(&mut [0u8; 4]).is_err());
+        assert!(FileRecord::new(&[0u8; RECORD_SIZE]).is_err());
+    }
+
+    #[test]
+    fn data_runs() {
+        // 0x18 clusters at LCN 0x5634, then 2 sparse clusters
+        let runs = decode_data_runs(&[0x21, 0x18, 0x34, 0x56, 0x01, 0x02, 0x00]).unwrap();
+        assert_eq!(runs, [
+            DataRun { lcn: Some(0x5634), length: 0x18 },
+            DataRun { lcn: None, length: 2 },
+        ]);
+
+        // LCN deltas can be negative, and span the full width
+        let expected = [(4, Some(100)), (2, None), (3, Some(50)), (0x10000, Some(0x0012_3456_789A)), (1, Some(0))];
+        let runs = decode_data_runs(&encode_runs(&expected)).unwrap();
+        let runs: Vec<_> = runs.iter().map(|r| (r.length, r.lcn)).collect();
+        assert_eq!(runs, expected);
+
+        // Ends at the first zero header even with bytes left over
+        assert!(decode_data_runs(&[0x00, 0x11, 0x01, 0x01]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_data_runs() {
+        // No length field, a truncated run, and an LCN before the start of the volume
+        assert!(decode_data_runs(&[0x10, 0x01, 0x00]).is_err());
+        assert!(decode_data_runs(&[0x31, 0x01, 0x00]).is_err());
+        assert!(decode_data_runs(&[0x11, 0x01, 0xFF, 0x00]).is_err());
+        assert!(decode_data_runs(&[0x91, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn record_attributes() {
+        let mut data = NonResident::new(ATTR_DATA, "", vec![(3, Some(40)), (1, None)], 13000);
+        data.initialized_size = 9000;
+        let r = record(7, IN_USE | DIRECTORY, reference(12, 3), &[
+            resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), "name", NAMESPACE_WIN32)),
+            resident(ATTR_DATA, "ads", b"stream"),
+            non_resident(&data),
+        ]);
+        let mut r = r.clone();
+        apply_fixups(&mut r).unwrap();
+
+        let record = FileRecord::new(&r).unwrap();
+        assert_eq!(record.sequence_number(), 7);
+        assert!(record.is_in_use());
+        assert!(record.is_directory());
+        assert_eq!(record.base_reference(), reference(12, 3));
+
+        let attributes: Vec<_> = record.attributes().collect::<Result<_>>().unwrap();
+        assert_eq!(attributes.iter().map(|a| a.type_code()).collect::<Vec<_>>(), [ATTR_FILE_NAME, ATTR_DATA, ATTR_DATA]);
+
+        let name = FileName::parse(attributes[0].value().unwrap()).unwrap();
+        assert_eq!((name.parent, name.namespace, name.name.as_str()), (reference(5, 5), NAMESPACE_WIN32, "name"));
+
+        let ads = &attributes[1];
+        assert_eq!(ads.name().unwrap(), "ads");
+        assert_eq!(ads.value().unwrap(), b"stream");
+        assert_eq!(ads.data_size(), 6);
+        assert!(ads.data_runs().is_err());
+
+        let data = &attributes[2];
+        assert!(data.is_unnamed() && data.is_non_resident());
+        assert!(data.value().is_err());
+        assert_eq!((data.data_size(), data.initialized_size(), data.allocated_size()), (13000, 9000, 4 * CLUSTER_SIZE as u64));
+        assert_eq!(data.data_runs().unwrap(), [DataRun { lcn: Some(40), length: 3 }, DataRun { lcn: None, length: 1 }]);
+    }
+
+    #[test]
+    fn attribute_with_invalid_length() {
+        let mut bad = resident(ATTR_DATA, "", b"x");
+        bad[4..8].copy_from_slice(&0x1000u32.to_le_bytes());
+        let mut r = record(1, IN_USE, FileReference::default(), &[resident(ATTR_DATA, "a", b"x"), bad, resident(ATTR_DATA, "b", b"x")]);
+        apply_fixups(&mut r).unwrap();
+
+        // The error ends the attributes, as nothing after it can be trusted
+        let attributes: Vec<_> = FileRecord::new(&r).unwrap().attributes().collect();
+        assert_eq!(attributes.len(), 2);
+        assert!(attributes[0].is_ok() && attributes[1].is_err());
+    }
+
+    #[test]
+    fn attribute_lists() {
+        let list = [
+            attribute_list_entry(ATTR_STANDARD_INFORMATION, "", 0, reference(40, 1)),
+            attribute_list_entry(ATTR_DATA, "stream", 0x80, reference(41, 2)),
+        ].concat();
+        let entries = parse_attribute_list(&list).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[1].type_code, entries[1].name.as_str()), (ATTR_DATA, "stream"));
+        assert_eq!((entries[1].lowest_vcn, entries[1].reference), (0x80, reference(41, 2)));
+
+        let mut bad = list.clone();
+        bad[4..6].copy_from_slice(&8u16.to_le_bytes());
+        assert!(parse_attribute_list(&bad).is_err());
+    }
+
+    #[test]
+    fn file_references() {
+        let r = FileReference::from_u64(0x0003_0000_0000_0029);
+        assert_eq!(r, reference(0x29, 3));
+        assert_eq!(r.to_u64(), 0x0003_0000_0000_0029);
+        assert!(r.matches(3) && !r.matches(4));
+        assert!(reference(0x29, 0).matches(4));
+        assert!(FileReference::default().is_null());
+    }
+}
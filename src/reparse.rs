@@ -0,0 +1,169 @@
+use anyhow::{bail, Result};
+use crate::bytes::{le_u16, le_u32};
+
+pub const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+pub const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+pub const IO_REPARSE_TAG_DEDUP: u32 = 0x8000_0013;
+pub const IO_REPARSE_TAG_WOF: u32 = 0x8000_0017;
+// IO_REPARSE_TAG_CLOUD through IO_REPARSE_TAG_CLOUD_F differ only in bits 12-15
+pub const IO_REPARSE_TAG_CLOUD: u32 = 0x9000_001A;
+const IO_REPARSE_TAG_CLOUD_MASK: u32 = 0xFFFF_0FFF;
+
+const SYMLINK_FLAG_RELATIVE: u32 = 0x0000_0001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReparseKind {
+    Symlink,
+    // Junctions, and volume mount points when the target is a `\??\Volume{...}` name
+    MountPoint,
+    // A Cloud Files API placeholder (OneDrive and friends), whose contents are fetched when first read
+    Cloud,
+    // Data Deduplication, whose contents live in the chunk store
+    Dedup,
+    // Windows Overlay Filter compression, whose contents live compressed in a named stream
+    Wof,
+    Other,
+}
+
+// A decoded $REPARSE_POINT attribute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReparsePoint {
+    pub tag: u32,
+    pub kind: ReparseKind,
+    // Substitute name of a symlink or mount point, e.g. `\??\C:\Users`
+    pub target: Option<String>,
+    pub relative: bool,
+}
+
+impl ReparsePoint {
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        if value.len() < 8 {
+            bail!("$REPARSE_POINT is truncated");
+        }
+
+        let tag = le_u32(value, 0);
+        let data_length = le_u16(value, 4) as usize;
+        if 8 + data_length > value.len() {
+            bail!("$REPARSE_POINT data is out of bounds");
+        }
+        let data = &value[8..8 + data_length];
+
+        let mut reparse_point = ReparsePoint {
+            tag,
+            kind: ReparseKind::Other,
+            target: None,
+            relative: false,
+        };
+        match tag {
+            IO_REPARSE_TAG_MOUNT_POINT => {
+                reparse_point.kind = ReparseKind::MountPoint;
+                reparse_point.target = Some(substitute_name(data, 8)?);
+            }
+            IO_REPARSE_TAG_SYMLINK => {
+                if data.len() < 12 {
+                    bail!("Symlink reparse data is truncated");
+                }
+                reparse_point.kind = ReparseKind::Symlink;
+                reparse_point.target = Some(substitute_name(data, 12)?);
+                reparse_point.relative = le_u32(data, 8) & SYMLINK_FLAG_RELATIVE != 0;
+            }
+            IO_REPARSE_TAG_DEDUP => reparse_point.kind = ReparseKind::Dedup,
+            IO_REPARSE_TAG_WOF => reparse_point.kind = ReparseKind::Wof,
+            _ if tag & IO_REPARSE_TAG_CLOUD_MASK == IO_REPARSE_TAG_CLOUD => reparse_point.kind = ReparseKind::Cloud,
+            _ => {}
+        }
+
+        Ok(reparse_point)
+    }
+
+    // True if reading the file's own clusters wouldn't give its contents. Reading a cloud placeholder through
+    // Windows would download it, so content-reading code must skip these rather than read them.
+    pub fn is_placeholder(&self) -> bool {
+        matches!(self.kind, ReparseKind::Cloud | ReparseKind::Dedup | ReparseKind::Wof)
+    }
+
+    pub fn description(&self) -> String {
+        let target = self.target.as_deref().unwrap_or("");
+        match self.kind {
+            ReparseKind::Symlink if self.relative => format!("Symlink to {} (relative)", target),
+            ReparseKind::Symlink => format!("Symlink to {}", target),
+            ReparseKind::MountPoint if target.starts_with(r"\??\Volume{") => format!("Volume mount point to {}", target),
+            ReparseKind::MountPoint => format!("Junction to {}", target),
+            // The provider registers for one of the 16 cloud tags, which is all the reparse data reliably says about it
+            ReparseKind::Cloud => format!("Cloud placeholder (provider tag {:X})", (self.tag >> 12) & 0xF),
+            ReparseKind::Dedup => String::from("Data Deduplication placeholder"),
+            ReparseKind::Wof => String::from("WOF compressed file"),
+            ReparseKind::Other => format!("Reparse point (tag 0x{:08X})", self.tag),
+        }
+    }
+}
+
+// Mount point and symlink data share a layout up to the path buffer, which starts at `path_offset`
+fn substitute_name(data: &[u8], path_offset: usize) -> Result<String> {
+    if data.len() < path_offset {
+        bail!("Reparse data is truncated");
+    }
+
+    let offset = path_offset + le_u16(data, 0) as usize;
+    let length = le_u16(data, 2) as usize;
+    if offset + length > data.len() {
+        bail!("Reparse substitute name is out of bounds");
+    }
+
+    let units: Vec<u16> = data[offset..offset + length].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Ok(String::from_utf16_lossy(&units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{reparse_point, utf16};
+
+    // Mount point data, or symlink data when there are `flags`
+    fn link_data(substitute: &str, print: &str, flags: Option<u32>) -> Vec<u8> {
+        let (substitute, print) = (utf16(substitute), utf16(print));
+        let mut data = Vec::new();
+        for n in [0, substitute.len(), substitute.len(), print.len()] {
+            data.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        if let Some(flags) = flags {
+            data.extend_from_slice(&flags.to_le_bytes());
+        }
+        data.extend_from_slice(&substitute);
+        data.extend_from_slice(&print);
+        data
+    }
+
+    #[test]
+    fn links() {
+        let junction = ReparsePoint::parse(&reparse_point(IO_REPARSE_TAG_MOUNT_POINT, &link_data(r"\??\C:\Users", r"C:\Users", None))).unwrap();
+        assert_eq!((junction.kind, junction.target.as_deref()), (ReparseKind::MountPoint, Some(r"\??\C:\Users")));
+        assert_eq!(junction.description(), r"Junction to \??\C:\Users");
+
+        let symlink = ReparsePoint::parse(&reparse_point(IO_REPARSE_TAG_SYMLINK, &link_data(r"..\a", r"..\a", Some(SYMLINK_FLAG_RELATIVE)))).unwrap();
+        assert_eq!((symlink.kind, symlink.target.as_deref(), symlink.relative), (ReparseKind::Symlink, Some(r"..\a"), true));
+        assert!(!symlink.is_placeholder());
+    }
+
+    #[test]
+    fn placeholders() {
+        for (tag, kind) in [(IO_REPARSE_TAG_CLOUD, ReparseKind::Cloud), (0x9000_F01A, ReparseKind::Cloud), (IO_REPARSE_TAG_DEDUP, ReparseKind::Dedup), (IO_REPARSE_TAG_WOF, ReparseKind::Wof)] {
+            let reparse_point = ReparsePoint::parse(&reparse_point(tag, &[0; 16])).unwrap();
+            assert_eq!(reparse_point.kind, kind);
+            assert!(reparse_point.is_placeholder());
+        }
+        assert_eq!(ReparsePoint::parse(&reparse_point(0x9000_F01A, &[])).unwrap().description(), "Cloud placeholder (provider tag F)");
+        assert_eq!(ReparsePoint::parse(&reparse_point(0x8000_0014, &[])).unwrap().kind, ReparseKind::Other);
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(ReparsePoint::parse(&[0; 4]).is_err());
+        let mut value = reparse_point(IO_REPARSE_TAG_SYMLINK, &link_data("target", "target", Some(0)));
+        assert!(ReparsePoint::parse(&value[..value.len() - 2]).is_err());
+        // A substitute name past the end of the data
+        value[8..10].copy_from_slice(&100u16.to_le_bytes());
+        assert!(ReparsePoint::parse(&value).is_err());
+        assert!(ReparsePoint::parse(&reparse_point(IO_REPARSE_TAG_SYMLINK, &[0; 8])).is_err());
+    }
+}
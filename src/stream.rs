@@ -0,0 +1,360 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use anyhow::{bail, Result};
+use crate::lznt1;
+use crate::mft::{read_runs, read_runs_at, AttributeValue, MftLayout, NonResidentValue};
+use crate::record::{FileRecord, FileReference, StandardInformation, ATTR_DATA, ATTR_REPARSE_POINT, ATTR_STANDARD_INFORMATION};
+use crate::reparse::ReparsePoint;
+use crate::volume::VolumeSource;
+use crate::{CompactVolumeIndex, VolumeIndexTree};
+
+// The contents of one $DATA stream, read straight from the volume rather than through Windows, so files that
+// are locked or that we have no access to can still be read. Compressed streams are decompressed as they're read.
+pub struct FileStream<'a, R: VolumeSource> {
+    reader: &'a mut R,
+    data: StreamData,
+    cluster_size: u64,
+    position: u64,
+    // The last compression unit decompressed, as reads are usually much smaller than a unit
+    unit: Option<(u64, Vec<u8>)>,
+}
+
+enum StreamData {
+    Resident(Vec<u8>),
+    NonResident(NonResidentValue),
+}
+
+impl<'a, R: VolumeSource> FileStream<'a, R> {
+    // The unnamed stream when `stream_name` is empty. Fails if `file` has been deleted, or its record reused,
+    // since the index was built, and for placeholders, which would read as zeros or as whatever part happens
+    // to be local.
+    pub fn open(reader: &'a mut R, layout: &MftLayout, file: FileReference, stream_name: &str) -> Result<Self> {
+        let record = layout.read_record(reader, file.entry)?;
+        let record = FileRecord::new(&record)?;
+        if !record.is_in_use() || !file.matches(record.sequence_number()) {
+            bail!("Record {} no longer holds the file", file.entry);
+        }
+        if is_placeholder(reader, layout, file.entry, &record)? {
+            bail!("Record {} is a placeholder, whose contents aren't stored in its streams", file.entry);
+        }
+
+        let data = match layout.read_attribute(reader, file.entry, ATTR_DATA, stream_name)? {
+            Some(AttributeValue::Resident(value)) => StreamData::Resident(value),
+            Some(AttributeValue::NonResident(value)) => {
+                // EFS encrypted data can only be decrypted with the user's key, through Windows
+                if value.encrypted {
+                    bail!("Stream is encrypted");
+                }
+                // Windows only ever uses 16 cluster units, anything much larger is corruption
+                if value.compressed && !(1..=8).contains(&value.compression_unit) {
+                    bail!("Stream has an invalid compression unit size");
+                }
+                StreamData::NonResident(value)
+            }
+            None if stream_name.is_empty() => bail!("Record {} has no unnamed stream", file.entry),
+            None => bail!("Record {} has no stream named {}", file.entry, stream_name),
+        };
+
+        Ok(FileStream {
+            reader,
+            data,
+            cluster_size: layout.boot_sector.cluster_size(),
+            position: 0,
+            unit: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            StreamData::Resident(value) => value.len() as u64,
+            StreamData::NonResident(value) => value.data_size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R: VolumeSource> Read for FileStream<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.len().saturating_sub(self.position) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let buf = &mut buf[..len];
+
+        match &self.data {
+            StreamData::Resident(value) => {
+                buf.copy_from_slice(&value[self.position as usize..self.position as usize + len]);
+            }
+            StreamData::NonResident(value) => {
+                // Clusters past the initialized size may hold anything, but read as zeros
+                let initialized = (value.initialized_size.saturating_sub(self.position) as usize).min(len);
+                if value.compressed {
+                    read_compressed(self.reader, value, self.cluster_size, &mut self.unit, self.position, &mut buf[..initialized])
+                } else {
+                    read_runs_at(self.reader, &value.runs, self.cluster_size, self.position, &mut buf[..initialized])
+                }.map_err(io::Error::other)?;
+                buf[initialized..].fill(0);
+            }
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: VolumeSource> Seek for FileStream<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match new_position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")),
+        }
+    }
+}
+
+fn read_compressed<R: VolumeSource>(reader: &mut R, value: &NonResidentValue, cluster_size: u64, cached: &mut Option<(u64, Vec<u8>)>, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let unit_size = cluster_size << value.compression_unit;
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let unit = position / unit_size;
+        if cached.as_ref().is_none_or(|(u, _)| *u != unit) {
+            let mut data = cached.take().map_or_else(|| vec![0; unit_size as usize], |(_, data)| data);
+            read_compression_unit(reader, value, cluster_size, unit, &mut data)?;
+            *cached = Some((unit, data));
+        }
+
+        let (_, data) = cached.as_ref().unwrap();
+        let start = (position % unit_size) as usize;
+        let len = (buf.len() - done).min(data.len() - start);
+        buf[done..done + len].copy_from_slice(&data[start..start + len]);
+        done += len;
+    }
+
+    Ok(())
+}
+
+// Each compression unit is stored one of three ways: sparse, when it's all zeros; in full, when compressing it
+// wouldn't save a cluster; or compressed into its first clusters, with the rest of the unit left sparse
+fn read_compression_unit<R: VolumeSource>(reader: &mut R, value: &NonResidentValue, cluster_size: u64, unit: u64, buf: &mut [u8]) -> Result<()> {
+    let unit_clusters = 1u64 << value.compression_unit;
+    let first_vcn = unit * unit_clusters;
+    let end_vcn = first_vcn + unit_clusters;
+
+    let mut allocated = 0;
+    let mut vcn = 0;
+    for run in &value.runs {
+        let start = vcn.max(first_vcn);
+        let end = (vcn + run.length).min(end_vcn);
+        if start < end && run.lcn.is_some() {
+            allocated += end - start;
+        }
+        vcn += run.length;
+        if vcn >= end_vcn {
+            break;
+        }
+    }
+
+    let offset = first_vcn * cluster_size;
+    if allocated == unit_clusters {
+        read_runs_at(reader, &value.runs, cluster_size, offset, buf)
+    } else if allocated == 0 {
+        buf.fill(0);
+        Ok(())
+    } else {
+        let mut compressed = vec![0u8; (allocated * cluster_size) as usize];
+        read_runs_at(reader, &value.runs, cluster_size, offset, &mut compressed)?;
+        lznt1::decompress(&compressed, buf)
+    }
+}
+
+// The same test as `FileMetadata::is_placeholder`, from the record as it is now rather than as indexed
+fn is_placeholder<R: VolumeSource>(reader: &mut R, layout: &MftLayout, index: u64, record: &FileRecord) -> Result<bool> {
+    // $STANDARD_INFORMATION is always in the base record
+    for attribute in record.attributes() {
+        let attribute = attribute?;
+        if attribute.type_code() == ATTR_STANDARD_INFORMATION
+            && StandardInformation::parse(attribute.value()?)?.file_attributes.is_recall_on_access() {
+            return Ok(true);
+        }
+    }
+
+    let reparse_data = match layout.read_attribute(reader, index, ATTR_REPARSE_POINT, "")? {
+        Some(AttributeValue::Resident(value)) => value,
+        Some(AttributeValue::NonResident(value)) => read_runs(reader, &value.runs, layout.boot_sector.cluster_size(), value.data_size)?,
+        None => return Ok(false),
+    };
+    Ok(ReparsePoint::parse(&reparse_data)?.is_placeholder())
+}
+
+// Opening many files reads the layout once, with `MftLayout::read`, and passes it to each `open_stream`
+impl VolumeIndexTree {
+    pub fn open_stream<'a, R: VolumeSource>(&self, reader: &'a mut R, layout: &MftLayout, inode: usize, stream_name: &str) -> Result<FileStream<'a, R>> {
+        let f = match self.0.get(inode).and_then(|f| f.as_ref()) {
+            Some(f) => f,
+            None => bail!("Record {} is not in the index", inode),
+        };
+
+        let file = FileReference {
+            entry: inode as u64,
+            sequence: f.sequence_number,
+        };
+        FileStream::open(reader, layout, file, stream_name)
+    }
+}
+
+impl CompactVolumeIndex {
+    pub fn open_stream<'a, R: VolumeSource>(&self, reader: &'a mut R, layout: &MftLayout, inode: usize, stream_name: &str) -> Result<FileStream<'a, R>> {
+        if !self.is_in_use(inode) {
+            bail!("Record {} is not in the index", inode);
+        }
+
+        let file = FileReference {
+            entry: inode as u64,
+            sequence: self.sequence_number(inode),
+        };
+        FileStream::open(reader, layout, file, stream_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::record::{FileAttributes, ATTR_FILE_NAME, NAMESPACE_WIN32};
+    use crate::reparse::IO_REPARSE_TAG_CLOUD;
+    use crate::testing::*;
+
+    fn open(volume: &mut Cursor<Vec<u8>>, file: FileReference) -> Result<Vec<u8>> {
+        let layout = MftLayout::read(volume)?;
+        let mut contents = Vec::new();
+        FileStream::open(volume, &layout, file, "")?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn placeholders_are_refused() {
+        let mut v = basic_volume();
+        let name = |n| resident(ATTR_FILE_NAME, "", &file_name(reference(5, 5), n, NAMESPACE_WIN32));
+        v.set_record(28, record(1, IN_USE, FileReference::default(), &[
+            resident(ATTR_STANDARD_INFORMATION, "", &standard_information([0; 4], FileAttributes::RECALL_ON_DATA_ACCESS)),
+            name("recall"),
+            resident(ATTR_DATA, "", b"partial"),
+        ]));
+        v.set_record(29, record(1, IN_USE, FileReference::default(), &[
+            resident(ATTR_STANDARD_INFORMATION, "", &standard_information([0; 4], 0)),
+            name("cloud"),
+            resident(ATTR_DATA, "", b"partial"),
+            resident(ATTR_REPARSE_POINT, "", &reparse_point(IO_REPARSE_TAG_CLOUD, &[0; 8])),
+        ]));
+        let mut volume = Cursor::new(v.finish());
+
+        assert_eq!(open(&mut volume, reference(31, 2)).unwrap(), b"hello world");
+        for placeholder in [reference(28, 1), reference(29, 1)] {
+            assert!(open(&mut volume, placeholder).unwrap_err().to_string().contains("placeholder"));
+        }
+        // Nor is a file whose record has been reused
+        assert!(open(&mut volume, reference(31, 3)).is_err());
+    }
+
+    #[test]
+    fn streams_through_an_index() {
+        let mut volume = Cursor::new(varied_volume().finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+        let (tree, _) = crate::VolumeIndexFlatArray::from_volume_reader(&mut volume, None).unwrap().build_tree();
+
+        let mut contents = Vec::new();
+        tree.open_stream(&mut volume, &layout, 26, "").unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, [7; 5000]);
+
+        let index = CompactVolumeIndex::from_tree(tree);
+        let mut stream = index.open_stream(&mut volume, &layout, 26, "Zone.Identifier").unwrap();
+        assert_eq!(stream.len(), 14);
+        let mut contents = String::new();
+        stream.seek(SeekFrom::Start(1)).unwrap();
+        stream.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "ZoneTransfer]");
+
+        assert!(index.open_stream(&mut volume, &layout, 26, "missing").is_err());
+        assert!(index.open_stream(&mut volume, &layout, 29, "").is_err());
+        assert!(index.open_stream(&mut volume, &layout, 5, "").is_err());
+    }
+
+    #[test]
+    fn past_the_initialized_size() {
+        let mut v = basic_volume();
+        v.write(40, &[3; 2 * CLUSTER_SIZE]);
+        let mut data = NonResident::new(ATTR_DATA, "", vec![(2, Some(40))], 6000);
+        data.initialized_size = 5000;
+        v.set_record(26, record(1, IN_USE, FileReference::default(), &[non_resident(&data)]));
+        let mut volume = Cursor::new(v.finish());
+
+        let contents = open(&mut volume, reference(26, 1)).unwrap();
+        assert_eq!(contents.len(), 6000);
+        assert!(contents[..5000].iter().all(|b| *b == 3));
+        assert!(contents[5000..].iter().all(|b| *b == 0));
+
+        let layout = MftLayout::read(&mut volume).unwrap();
+        let mut stream = FileStream::open(&mut volume, &layout, reference(26, 1), "").unwrap();
+        assert_eq!(stream.seek(SeekFrom::End(-10)).unwrap(), 5990);
+        assert!(stream.seek(SeekFrom::Current(-6000)).is_err());
+        assert_eq!(stream.seek(SeekFrom::End(10)).unwrap(), 6010);
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn compressed_streams() {
+        let unit = 16 * CLUSTER_SIZE;
+        // Unit 0 compressed into clusters 40-41, unit 1 sparse, and unit 2 stored in full at 48
+        let size = 2 * unit + 5000;
+        let mut data = NonResident::new(ATTR_DATA, "", vec![(2, Some(40)), (14, None), (16, None), (16, Some(48))], size as u64);
+        data.flags = 0x0001;
+        data.compression_unit = 4;
+        data.total_allocated = Some(18 * CLUSTER_SIZE as u64);
+        // The last chunk is short, and decompresses to less than 4 KB
+        let plain: Vec<u8> = (0..unit - 1000).map(|i| b"the quick brown fox "[i % 20] ^ (i / 4096) as u8).collect();
+        let packed = lznt1_compress(&plain);
+        assert!(packed.len() < 2 * CLUSTER_SIZE);
+        let stored: Vec<u8> = (0..unit).map(|i| (i % 251) as u8).collect();
+
+        let volume_with = |packed: &[u8]| {
+            let mut v = basic_volume();
+            v.set_record(26, record(1, IN_USE, FileReference::default(), &[non_resident(&data)]));
+            v.write(40, packed);
+            v.write(48, &stored);
+            Cursor::new(v.finish())
+        };
+        let mut volume = volume_with(&packed);
+        let contents = open(&mut volume, reference(26, 1)).unwrap();
+        assert_eq!(contents.len(), size);
+        assert_eq!(contents[..plain.len()], plain[..]);
+        assert!(contents[plain.len()..2 * unit].iter().all(|b| *b == 0));
+        assert_eq!(contents[2 * unit..], stored[..5000]);
+
+        // Small reads across a chunk boundary, and back into an earlier unit
+        let layout = MftLayout::read(&mut volume).unwrap();
+        let mut stream = FileStream::open(&mut volume, &layout, reference(26, 1), "").unwrap();
+        let mut four = [0u8; 4];
+        for (position, expected) in [(4094, &plain[4094..4098]), (2 * unit - 2, &[0, 0, 0, 1][..]), (10, &plain[10..14])] {
+            stream.seek(SeekFrom::Start(position as u64)).unwrap();
+            stream.read_exact(&mut four).unwrap();
+            assert_eq!(four, expected);
+        }
+
+        // Corrupt data is an error rather than a panic
+        let mut corrupt = packed.clone();
+        corrupt[2..5].copy_from_slice(&[0x01, 0xFF, 0xFF]);
+        assert!(open(&mut volume_with(&corrupt), reference(26, 1)).is_err());
+    }
+}
+
@@ -0,0 +1,401 @@
+// Hand-built NTFS structures for the unit tests: attributes, file records with their fixups, and small volumes
+// with a boot sector and an MFT holding those records
+
+use crate::record::FileReference;
+use crate::FileMetadata;
+
+pub const CLUSTER_SIZE: usize = 4096;
+pub const RECORD_SIZE: usize = 1024;
+
+// File record flags
+pub const IN_USE: u16 = 0x0001;
+pub const DIRECTORY: u16 = 0x0002;
+
+const UPDATE_SEQUENCE_NUMBER: [u8; 2] = [0x34, 0x12];
+
+pub fn reference(entry: u64, sequence: u16) -> FileReference {
+    FileReference { entry, sequence }
+}
+
+pub fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+fn align8(n: usize) -> usize {
+    n.next_multiple_of(8)
+}
+
+pub fn resident(type_code: u32, name: &str, value: &[u8]) -> Vec<u8> {
+    resident_with_flags(type_code, name, value, 0)
+}
+
+pub fn resident_with_flags(type_code: u32, name: &str, value: &[u8], flags: u16) -> Vec<u8> {
+    let name = utf16(name);
+    let name_offset = 24;
+    let value_offset = align8(name_offset + name.len());
+    let length = align8(value_offset + value.len());
+
+    let mut a = vec![0u8; length];
+    a[0..4].copy_from_slice(&type_code.to_le_bytes());
+    a[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+    a[9] = (name.len() / 2) as u8;
+    a[10..12].copy_from_slice(&(name_offset as u16).to_le_bytes());
+    a[12..14].copy_from_slice(&flags.to_le_bytes());
+    a[16..20].copy_from_slice(&(value.len() as u32).to_le_bytes());
+    a[20..22].copy_from_slice(&(value_offset as u16).to_le_bytes());
+    a[name_offset..name_offset + name.len()].copy_from_slice(&name);
+    a[value_offset..value_offset + value.len()].copy_from_slice(value);
+    a
+}
+
+// One piece of a non-resident attribute. Runs are (clusters, LCN), with no LCN for sparse runs.
+pub struct NonResident<'a> {
+    pub type_code: u32,
+    pub name: &'a str,
+    pub lowest_vcn: u64,
+    pub runs: Vec<(u64, Option<u64>)>,
+    pub allocated_size: u64,
+    pub data_size: u64,
+    pub initialized_size: u64,
+    pub compression_unit: u8,
+    pub flags: u16,
+    // Only written for compressed and sparse attributes
+    pub total_allocated: Option<u64>,
+}
+
+impl<'a> NonResident<'a> {
+    pub fn new(type_code: u32, name: &'a str, runs: Vec<(u64, Option<u64>)>, data_size: u64) -> Self {
+        let clusters: u64 = runs.iter().map(|(length, _)| length).sum();
+        NonResident {
+            type_code,
+            name,
+            lowest_vcn: 0,
+            runs,
+            allocated_size: clusters * CLUSTER_SIZE as u64,
+            data_size,
+            initialized_size: data_size,
+            compression_unit: 0,
+            flags: 0,
+            total_allocated: None,
+        }
+    }
+}
+
+pub fn non_resident(attribute: &NonResident) -> Vec<u8> {
+    let name = utf16(attribute.name);
+    let name_offset = if attribute.total_allocated.is_some() { 72 } else { 64 };
+    let runs_offset = align8(name_offset + name.len());
+    let runs = encode_runs(&attribute.runs);
+    let length = align8(runs_offset + runs.len());
+    let clusters: u64 = attribute.runs.iter().map(|(length, _)| length).sum();
+
+    let mut a = vec![0u8; length];
+    a[0..4].copy_from_slice(&attribute.type_code.to_le_bytes());
+    a[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+    a[8] = 1;
+    a[9] = (name.len() / 2) as u8;
+    a[10..12].copy_from_slice(&(name_offset as u16).to_le_bytes());
+    a[12..14].copy_from_slice(&attribute.flags.to_le_bytes());
+    a[16..24].copy_from_slice(&attribute.lowest_vcn.to_le_bytes());
+    a[24..32].copy_from_slice(&(attribute.lowest_vcn + clusters).saturating_sub(1).to_le_bytes());
+    a[32..34].copy_from_slice(&(runs_offset as u16).to_le_bytes());
+    a[34] = attribute.compression_unit;
+    a[40..48].copy_from_slice(&attribute.allocated_size.to_le_bytes());
+    a[48..56].copy_from_slice(&attribute.data_size.to_le_bytes());
+    a[56..64].copy_from_slice(&attribute.initialized_size.to_le_bytes());
+    if let Some(total_allocated) = attribute.total_allocated {
+        a[64..72].copy_from_slice(&total_allocated.to_le_bytes());
+    }
+    a[name_offset..name_offset + name.len()].copy_from_slice(&name);
+    a[runs_offset..runs_offset + runs.len()].copy_from_slice(&runs);
+    a
+}
+
+// The inverse of `decode_data_runs`, using the fewest bytes for each field
+pub fn encode_runs(runs: &[(u64, Option<u64>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut previous_lcn = 0i64;
+    for (length, lcn) in runs {
+        let length = minimal_bytes(&length.to_le_bytes(), false);
+        let offset = match lcn {
+            Some(lcn) => {
+                let delta = *lcn as i64 - previous_lcn;
+                previous_lcn = *lcn as i64;
+                minimal_bytes(&delta.to_le_bytes(), true)
+            }
+            None => Vec::new(),
+        };
+        out.push((offset.len() << 4 | length.len()) as u8);
+        out.extend(length);
+        out.extend(offset);
+    }
+    out.push(0);
+    out
+}
+
+fn minimal_bytes(bytes: &[u8], signed: bool) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    while bytes.len() > 1 {
+        let (last, next) = (bytes[bytes.len() - 1], bytes[bytes.len() - 2]);
+        let redundant = if signed {
+            (last == 0 && next & 0x80 == 0) || (last == 0xFF && next & 0x80 != 0)
+        } else {
+            last == 0
+        };
+        if !redundant {
+            break;
+        }
+        bytes.pop();
+    }
+    bytes
+}
+
+pub fn file_name(parent: FileReference, name: &str, namespace: u8) -> Vec<u8> {
+    file_name_with_times(parent, name, namespace, [0; 4])
+}
+
+// Times are created, modified, MFT modified and accessed, in FILETIME units
+pub fn file_name_with_times(parent: FileReference, name: &str, namespace: u8, times: [u64; 4]) -> Vec<u8> {
+    let name = utf16(name);
+    let mut v = vec![0u8; 0x42 + name.len()];
+    v[0..8].copy_from_slice(&parent.to_u64().to_le_bytes());
+    for (i, time) in times.iter().enumerate() {
+        v[8 + i * 8..16 + i * 8].copy_from_slice(&time.to_le_bytes());
+    }
+    v[0x40] = (name.len() / 2) as u8;
+    v[0x41] = namespace;
+    v[0x42..].copy_from_slice(&name);
+    v
+}
+
+// Times are in the same order as for `file_name_with_times`
+pub fn standard_information(times: [u64; 4], file_attributes: u32) -> Vec<u8> {
+    let mut v = vec![0u8; 0x48];
+    for (i, time) in times.iter().enumerate() {
+        v[i * 8..8 + i * 8].copy_from_slice(&time.to_le_bytes());
+    }
+    v[0x20..0x24].copy_from_slice(&file_attributes.to_le_bytes());
+    v
+}
+
+pub fn attribute_list_entry(type_code: u32, name: &str, lowest_vcn: u64, record: FileReference) -> Vec<u8> {
+    let name = utf16(name);
+    let length = align8(26 + name.len());
+    let mut e = vec![0u8; length];
+    e[0..4].copy_from_slice(&type_code.to_le_bytes());
+    e[4..6].copy_from_slice(&(length as u16).to_le_bytes());
+    e[6] = (name.len() / 2) as u8;
+    e[7] = 26;
+    e[8..16].copy_from_slice(&lowest_vcn.to_le_bytes());
+    e[16..24].copy_from_slice(&record.to_u64().to_le_bytes());
+    e[26..26 + name.len()].copy_from_slice(&name);
+    e
+}
+
+// A $REPARSE_POINT value
+pub fn reparse_point(tag: u32, data: &[u8]) -> Vec<u8> {
+    let mut v = vec![0u8; 8];
+    v[0..4].copy_from_slice(&tag.to_le_bytes());
+    v[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    v.extend_from_slice(data);
+    v
+}
+
+// A record as it is on disk, with the last two bytes of each stride swapped out for the update sequence number
+pub fn record(sequence: u16, flags: u16, base: FileReference, attributes: &[Vec<u8>]) -> Vec<u8> {
+    let mut r = vec![0u8; RECORD_SIZE];
+    r[0..4].copy_from_slice(b"FILE");
+    r[4..6].copy_from_slice(&0x30u16.to_le_bytes());
+    r[6..8].copy_from_slice(&3u16.to_le_bytes());
+    r[0x10..0x12].copy_from_slice(&sequence.to_le_bytes());
+    r[0x14..0x16].copy_from_slice(&0x38u16.to_le_bytes());
+    r[0x16..0x18].copy_from_slice(&flags.to_le_bytes());
+    r[0x1C..0x20].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+    r[0x20..0x28].copy_from_slice(&base.to_u64().to_le_bytes());
+
+    let mut offset = 0x38;
+    for a in attributes {
+        r[offset..offset + a.len()].copy_from_slice(a);
+        offset += a.len();
+    }
+    r[offset..offset + 4].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+    offset += 8;
+    r[0x18..0x1C].copy_from_slice(&(offset as u32).to_le_bytes());
+
+    // Attributes may cross the end of a stride, as its last two bytes are saved in the update sequence array
+    r[0x30..0x32].copy_from_slice(&UPDATE_SEQUENCE_NUMBER);
+    for i in 1..3 {
+        let end = i * 512;
+        r.copy_within(end - 2..end, 0x30 + i * 2);
+        r[end - 2..end].copy_from_slice(&UPDATE_SEQUENCE_NUMBER);
+    }
+    r
+}
+
+// A volume of 4 KB clusters and 1 KB records, with the MFT in the given (LCN, clusters) runs
+pub struct TestVolume {
+    pub data: Vec<u8>,
+    pub mft_runs: Vec<(u64, u64)>,
+    pub records: Vec<Option<Vec<u8>>>,
+}
+
+impl TestVolume {
+    pub fn new(clusters: usize, mft_runs: Vec<(u64, u64)>) -> Self {
+        let mft_clusters: u64 = mft_runs.iter().map(|(_, clusters)| clusters).sum();
+        TestVolume {
+            data: vec![0u8; clusters * CLUSTER_SIZE],
+            mft_runs,
+            records: vec![None; mft_clusters as usize * CLUSTER_SIZE / RECORD_SIZE],
+        }
+    }
+
+    pub fn set_record(&mut self, index: usize, record: Vec<u8>) {
+        self.records[index] = Some(record);
+    }
+
+    pub fn write(&mut self, lcn: u64, bytes: &[u8]) {
+        let offset = lcn as usize * CLUSTER_SIZE;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // The unnamed $DATA of $MFT, mapping every record
+    pub fn mft_data(&self) -> Vec<u8> {
+        let runs = self.mft_runs.iter().map(|(lcn, clusters)| (*clusters, Some(*lcn))).collect();
+        non_resident(&NonResident::new(0x80, "", runs, (self.records.len() * RECORD_SIZE) as u64))
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut boot = vec![0u8; 512];
+        boot[3..11].copy_from_slice(b"NTFS    ");
+        boot[0x0B..0x0D].copy_from_slice(&512u16.to_le_bytes());
+        boot[0x0D] = (CLUSTER_SIZE / 512) as u8;
+        boot[0x28..0x30].copy_from_slice(&((self.data.len() / 512 - 1) as u64).to_le_bytes());
+        boot[0x30..0x38].copy_from_slice(&self.mft_runs[0].0.to_le_bytes());
+        boot[0x38..0x40].copy_from_slice(&2u64.to_le_bytes());
+        boot[0x40] = -10i8 as u8;
+        boot[0x44] = 1;
+        boot[0x48..0x50].copy_from_slice(&0xDEAD_BEEFu64.to_le_bytes());
+        boot[510..512].copy_from_slice(&0xAA55u16.to_le_bytes());
+        self.data[..512].copy_from_slice(&boot);
+
+        let mut mft = vec![0u8; self.records.len() * RECORD_SIZE];
+        for (i, record) in self.records.iter().enumerate() {
+            if let Some(record) = record {
+                mft[i * RECORD_SIZE..(i + 1) * RECORD_SIZE].copy_from_slice(record);
+            }
+        }
+        let mut offset = 0;
+        for (lcn, clusters) in self.mft_runs.clone() {
+            let length = clusters as usize * CLUSTER_SIZE;
+            self.write(lcn, &mft[offset..offset + length]);
+            offset += length;
+        }
+
+        self.data
+    }
+}
+
+// $MFT, the root directory (5), the directory "docs" (30) in the root, and "a.txt" (31) in docs. The MFT is in
+// two pieces, the second of which comes first on the volume.
+pub fn basic_volume() -> TestVolume {
+    let mut v = TestVolume::new(64, vec![(20, 4), (8, 4)]);
+    let mft = record(1, IN_USE, FileReference::default(), &[
+        resident(0x30, "", &file_name(reference(5, 5), "$MFT", 3)),
+        v.mft_data(),
+    ]);
+    v.set_record(0, mft);
+    v.set_record(5, record(5, IN_USE | DIRECTORY, FileReference::default(), &[
+        resident(0x30, "", &file_name(reference(5, 5), ".", 3)),
+    ]));
+    v.set_record(30, record(1, IN_USE | DIRECTORY, FileReference::default(), &[
+        resident(0x30, "", &file_name(reference(5, 5), "docs", 1)),
+    ]));
+    v.set_record(31, record(2, IN_USE, FileReference::default(), &[
+        resident(0x30, "", &file_name(reference(30, 1), "a.txt", 1)),
+        resident(0x80, "", b"hello world"),
+    ]));
+    v
+}
+
+// `basic_volume` plus "b.bin" (26), hidden and hard linked in both the root and docs, with distinct times, a
+// non-resident unnamed stream and a named one, and "link" (27), a symlink in the root
+pub fn varied_volume() -> TestVolume {
+    let mut v = basic_volume();
+    v.write(40, &[7; 5000]);
+    v.set_record(26, record(3, IN_USE, FileReference::default(), &[
+        resident(0x10, "", &standard_information([11, 12, 13, 14], 0x22)),
+        resident(0x30, "", &file_name_with_times(reference(5, 5), "b.bin", 1, [21, 22, 23, 24])),
+        resident(0x30, "", &file_name(reference(30, 1), "b again.bin", 1)),
+        non_resident(&NonResident::new(0x80, "", vec![(2, Some(40))], 5000)),
+        resident(0x80, "Zone.Identifier", b"[ZoneTransfer]"),
+    ]));
+    let target = utf16(r"\??\C:\a");
+    let mut symlink = [(0u16).to_le_bytes(), (target.len() as u16).to_le_bytes(), (0u16).to_le_bytes(), (0u16).to_le_bytes()].concat();
+    symlink.extend_from_slice(&[0; 4]);
+    symlink.extend_from_slice(&target);
+    v.set_record(27, record(1, IN_USE, FileReference::default(), &[
+        resident(0x30, "", &file_name(reference(5, 5), "link", 1)),
+        resident(0xC0, "", &reparse_point(0xA000_000C, &symlink)),
+    ]));
+    v
+}
+
+// Everything about a file that an index keeps, to compare files across index types
+pub fn describe(f: &FileMetadata) -> String {
+    format!(
+        "{} {} {:?} {} {:?} {:?} {} {} {:?} {:?} {:?} {:?} {:?}",
+        f.index, f.sequence_number, f.links, f.is_dir, f.attributes, f.reparse_point, f.file_size, f.allocated_size,
+        f.streams, f.standard_information_times, f.file_name_times, f.children, f.totals,
+    )
+}
+
+// A greedy LZNT1 compressor, the inverse of `lznt1::decompress`. Slow, but small inputs are all tests need.
+pub fn lznt1_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(4096) {
+        let mut body = Vec::new();
+        let mut position = 0;
+        while position < chunk.len() {
+            let flags = body.len();
+            body.push(0u8);
+            for bit in 0..8 {
+                if position >= chunk.len() {
+                    break;
+                }
+
+                // The same split of a back-reference's bits between offset and length as when decompressing
+                let mut length_bits = 12;
+                let mut reach = position.saturating_sub(1);
+                while reach >= 0x10 {
+                    reach >>= 1;
+                    length_bits -= 1;
+                }
+                let max_length = (1 << length_bits) + 2;
+
+                let (mut offset, mut length) = (0, 0);
+                for o in 1..=position.min(1 << (16 - length_bits)) {
+                    let mut l = 0;
+                    while position + l < chunk.len() && l < max_length && chunk[position + l - o] == chunk[position + l] {
+                        l += 1;
+                    }
+                    if l > length {
+                        (offset, length) = (o, l);
+                    }
+                }
+
+                if length >= 3 {
+                    body[flags] |= 1 << bit;
+                    body.extend_from_slice(&((((offset - 1) << length_bits) | (length - 3)) as u16).to_le_bytes());
+                    position += length;
+                } else {
+                    body.push(chunk[position]);
+                    position += 1;
+                }
+            }
+        }
+
+        out.extend_from_slice(&(0xB000 | (body.len() as u16 - 1)).to_le_bytes());
+        out.extend_from_slice(&body);
+    }
+    out
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+
+// Seconds from 1601-01-01, the FILETIME epoch, to 1970-01-01
+const FILETIME_UNIX_EPOCH_SECONDS: i64 = 11_644_473_600;
+const FILETIME_TICKS_PER_SECOND: u64 = 10_000_000;
+
+// A Windows FILETIME as NTFS stores it: 100 ns ticks since 1601-01-01 UTC
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileTime(pub u64);
+
+impl FileTime {
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn unix_seconds(&self) -> i64 {
+        (self.0 / FILETIME_TICKS_PER_SECOND) as i64 - FILETIME_UNIX_EPOCH_SECONDS
+    }
+}
+
+// Formats as `YYYY-MM-DD HH:MM:SS` in UTC
+impl fmt::Display for FileTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "-");
+        }
+
+        let seconds = self.unix_seconds();
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let time = seconds.rem_euclid(86400);
+
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+    }
+}
+
+// The four times NTFS keeps in both $STANDARD_INFORMATION and $FILE_NAME, in on-disk order.
+// $STANDARD_INFORMATION is what Windows shows and updates; the $FILE_NAME copy only changes when the
+// name does, which makes it harder to forge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamps {
+    pub created: FileTime,
+    pub modified: FileTime,
+    // When the MFT record itself last changed
+    pub mft_modified: FileTime,
+    pub accessed: FileTime,
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(FileTime(0).to_string(), "-");
+        assert_eq!(FileTime(10_000_000).to_string(), "1601-01-01 00:00:01");
+        assert_eq!(FileTime(116_444_736_000_000_000).to_string(), "1970-01-01 00:00:00");
+        assert_eq!(FileTime(125_963_012_960_000_000).to_string(), "2000-02-29 12:34:56");
+        // Ticks within a second are dropped rather than rounded
+        assert_eq!(FileTime(133_801_631_999_999_999).to_string(), "2024-12-31 23:59:59");
+    }
+
+    #[test]
+    fn unix_seconds() {
+        assert_eq!(FileTime(116_444_736_000_000_000).unix_seconds(), 0);
+        assert_eq!(FileTime(125_963_012_960_000_000).unix_seconds(), 951_827_696);
+        assert_eq!(FileTime(0).unix_seconds(), -FILETIME_UNIX_EPOCH_SECONDS);
+    }
+}
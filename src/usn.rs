@@ -0,0 +1,340 @@
+use anyhow::{bail, Result};
+use ntfs::KnownNtfsFileRecordNumber::Extend;
+use crate::bytes::{le_u16, le_u32, le_u64};
+use crate::mft::{read_runs_at, AttributeValue, MftLayout, MFT_CHUNK_SIZE};
+use crate::parse::read_file;
+use crate::record::{DataRun, FileReference, ATTR_DATA};
+use crate::time::FileTime;
+use crate::volume::VolumeSource;
+use crate::VolumeIndexFlatArray;
+
+const USN_JOURNAL_NAME: &str = "$UsnJrnl";
+// The records themselves, and the journal's settings
+const USN_JOURNAL_DATA_STREAM: &str = "$J";
+const USN_JOURNAL_MAX_STREAM: &str = "$Max";
+// Records never cross a page, the end of which is left zeroed when the next record doesn't fit
+const USN_PAGE_SIZE: u64 = 4096;
+
+// Where the journal was up to, as stored alongside a cached index. The journal ID changes whenever the journal
+// is deleted and recreated, after which USNs start again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JournalPosition {
+    pub journal_id: u64,
+    pub next_usn: u64,
+}
+
+// One USN_RECORD_V2 or USN_RECORD_V3, each logging one or more changes to a file
+#[derive(Clone, Debug)]
+pub struct UsnRecord {
+    pub usn: u64,
+    pub file_reference: FileReference,
+    pub parent: FileReference,
+    pub timestamp: FileTime,
+    // USN_REASON_* flags
+    pub reason: u32,
+    pub name: String,
+}
+
+impl UsnRecord {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            bail!("USN record is truncated");
+        }
+
+        // Version 3 has 128 bit file references, of which NTFS only uses the low 64 bits
+        let (reference_size, fields) = match le_u16(buf, 4) {
+            2 => (8, 0x18),
+            3 => (16, 0x28),
+            version => bail!("USN record version {} is not supported", version),
+        };
+        if buf.len() < fields + 0x24 {
+            bail!("USN record is truncated");
+        }
+
+        let name_length = le_u16(buf, fields + 0x20) as usize;
+        let name_offset = le_u16(buf, fields + 0x22) as usize;
+        if name_offset + name_length > buf.len() {
+            bail!("USN record name is out of bounds");
+        }
+        let units: Vec<u16> = buf[name_offset..name_offset + name_length].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+
+        Ok(UsnRecord {
+            usn: le_u64(buf, fields),
+            file_reference: FileReference::from_u64(le_u64(buf, 8)),
+            parent: FileReference::from_u64(le_u64(buf, 8 + reference_size)),
+            timestamp: FileTime(le_u64(buf, fields + 8)),
+            reason: le_u32(buf, fields + 0x10),
+            name: String::from_utf16_lossy(&units),
+        })
+    }
+}
+
+// $Extend\$UsnJrnl, whose $J stream is the journal itself. USNs are byte offsets into $J, and once the journal
+// reaches its maximum size the start of $J is freed, leaving it sparse up to the lowest valid USN.
+pub struct UsnJournal {
+    pub record: u64,
+    pub journal_id: u64,
+    pub lowest_valid_usn: u64,
+    data_size: u64,
+    runs: Vec<DataRun>,
+    cluster_size: u64,
+}
+
+impl UsnJournal {
+    // `index` is only used to find the journal's record. None when the volume has no journal.
+    pub fn open<R: VolumeSource>(reader: &mut R, layout: &MftLayout, index: &VolumeIndexFlatArray) -> Result<Option<Self>> {
+        let record = index.0.iter().flatten().find(|f| {
+            f.links.iter().any(|l| l.parent.entry == Extend as u64 && l.name == USN_JOURNAL_NAME)
+        });
+        let record = match record {
+            Some(f) => f.index,
+            None => return Ok(None),
+        };
+
+        let max = match layout.read_attribute(reader, record, ATTR_DATA, USN_JOURNAL_MAX_STREAM)? {
+            Some(AttributeValue::Resident(max)) if max.len() >= 0x20 => max,
+            _ => return Ok(None),
+        };
+        let (data_size, runs) = match layout.read_attribute(reader, record, ATTR_DATA, USN_JOURNAL_DATA_STREAM)? {
+            Some(AttributeValue::NonResident(j)) => (j.data_size, j.runs),
+            // Only a journal with no records yet is small enough to be resident
+            Some(AttributeValue::Resident(_)) => (0, Vec::new()),
+            None => return Ok(None),
+        };
+
+        Ok(Some(UsnJournal {
+            record,
+            journal_id: le_u64(&max, 0x10),
+            lowest_valid_usn: le_u64(&max, 0x18),
+            data_size,
+            runs,
+            cluster_size: layout.boot_sector.cluster_size(),
+        }))
+    }
+
+    // Just past the last record
+    pub fn position(&self) -> JournalPosition {
+        JournalPosition {
+            journal_id: self.journal_id,
+            next_usn: self.data_size,
+        }
+    }
+
+    // Where the journal was when the scan that built `index` read the journal's own record. There's no way to
+    // find the journal before scanning, so changes to files read earlier in the scan that were made before the
+    // journal was read are missed until those files change again.
+    pub fn position_in(&self, index: &VolumeIndexFlatArray) -> Option<JournalPosition> {
+        let journal = index.0.get(self.record as usize)?.as_ref()?;
+        let stream = journal.streams.iter().find(|s| s.name == USN_JOURNAL_DATA_STREAM)?;
+
+        Some(JournalPosition {
+            journal_id: self.journal_id,
+            next_usn: stream.size,
+        })
+    }
+
+    // Every record logged since `since`, or None if they're no longer all in the journal, because it was
+    // recreated or has since wrapped past them
+    pub fn read_since<R: VolumeSource>(&self, reader: &mut R, since: JournalPosition) -> Result<Option<Vec<UsnRecord>>> {
+        if since.journal_id != self.journal_id || since.next_usn < self.lowest_valid_usn || since.next_usn > self.data_size {
+            return Ok(None);
+        }
+
+        let mut records = Vec::new();
+        let mut chunk = vec![0u8; MFT_CHUNK_SIZE];
+        let mut position = since.next_usn;
+        // Chunks start on a page, so pages never span two chunks
+        let mut chunk_start = position - position % USN_PAGE_SIZE;
+        while chunk_start < self.data_size {
+            let len = (MFT_CHUNK_SIZE as u64).min(self.data_size - chunk_start) as usize;
+            let buf = &mut chunk[..len];
+            read_runs_at(reader, &self.runs, self.cluster_size, chunk_start, buf)?;
+
+            let chunk_end = chunk_start + len as u64;
+            while position < chunk_end {
+                let page_end = ((position / USN_PAGE_SIZE + 1) * USN_PAGE_SIZE).min(chunk_end);
+                let rest = &buf[(position - chunk_start) as usize..(page_end - chunk_start) as usize];
+                let length = if rest.len() >= 4 { le_u32(rest, 0) as usize } else { 0 };
+                if length == 0 {
+                    position = page_end;
+                    continue;
+                }
+                if length < 8 || length % 8 != 0 || length > rest.len() {
+                    bail!("USN record at {} has an invalid length", position);
+                }
+
+                // Version 4 records only say which ranges of a file changed, alongside a version 3 record
+                if let Ok(record) = UsnRecord::parse(&rest[..length]) {
+                    records.push(record);
+                }
+                position += length as u64;
+            }
+
+            chunk_start = chunk_end;
+        }
+
+        Ok(Some(records))
+    }
+}
+
+impl VolumeIndexFlatArray {
+    // Where the journal was when this index was scanned, for storing with a cached index
+    pub fn journal_position<R: VolumeSource>(&self, reader: &mut R) -> Result<Option<JournalPosition>> {
+        let layout = MftLayout::read(reader)?;
+        Ok(UsnJournal::open(reader, &layout, self)?.and_then(|j| j.position_in(self)))
+    }
+
+    // Brings the index up to date by reading every file the journal logged a change to since `since` again,
+    // returning the new position. None when the journal no longer goes back that far, and the volume has to
+    // be scanned again.
+    pub fn update_from_journal<R: VolumeSource>(&mut self, reader: &mut R, since: JournalPosition) -> Result<Option<JournalPosition>> {
+        let layout = MftLayout::read(reader)?;
+        let journal = match UsnJournal::open(reader, &layout, self)? {
+            Some(j) => j,
+            None => return Ok(None),
+        };
+        let records = match journal.read_since(reader, since)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        // The journal doesn't have sizes, so creates, renames, deletes and size changes are all handled by
+        // reading the file's record again. Parents too, as adding or removing a file changes a directory's times.
+        let mut changed: Vec<u64> = records.iter().flat_map(|r| [r.file_reference.entry, r.parent.entry]).collect();
+        changed.sort_unstable();
+        changed.dedup();
+
+        // The MFT never shrinks
+        if (self.0.len() as u64) < layout.record_count {
+            self.0.resize_with(layout.record_count as usize, || None);
+        }
+        for index in changed.into_iter().filter(|i| *i < layout.record_count) {
+            self.0[index as usize] = read_file(reader, &layout, index)?;
+        }
+
+        Ok(Some(journal.position()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::record::{ATTR_FILE_NAME, NAMESPACE_WIN32};
+    use crate::testing::*;
+
+    const USN_REASON_FILE_CREATE: u32 = 0x0000_0100;
+    const USN_REASON_RENAME_NEW_NAME: u32 = 0x0000_2000;
+    const JOURNAL_ID: u64 = 0x01D0_0000_0000_0001;
+
+    fn usn_record(version: u16, usn: u64, file: FileReference, parent: FileReference, reason: u32, name: &str) -> Vec<u8> {
+        let (reference_size, fields) = if version == 2 { (8, 0x18) } else { (16, 0x28) };
+        let name = utf16(name);
+        let name_offset = fields + 0x24;
+        let length = (name_offset + name.len()).next_multiple_of(8);
+
+        let mut r = vec![0u8; length];
+        r[0..4].copy_from_slice(&(length as u32).to_le_bytes());
+        r[4..6].copy_from_slice(&version.to_le_bytes());
+        r[8..16].copy_from_slice(&file.to_u64().to_le_bytes());
+        r[8 + reference_size..16 + reference_size].copy_from_slice(&parent.to_u64().to_le_bytes());
+        r[fields..fields + 8].copy_from_slice(&usn.to_le_bytes());
+        r[fields + 8..fields + 16].copy_from_slice(&0x01DA_0000_0000_0000u64.to_le_bytes());
+        r[fields + 0x10..fields + 0x14].copy_from_slice(&reason.to_le_bytes());
+        r[fields + 0x20..fields + 0x22].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        r[fields + 0x22..fields + 0x24].copy_from_slice(&(name_offset as u16).to_le_bytes());
+        r[name_offset..name_offset + name.len()].copy_from_slice(&name);
+        r
+    }
+
+    #[test]
+    fn parse_records() {
+        for version in [2, 3] {
+            let record = UsnRecord::parse(&usn_record(version, 4096, reference(31, 2), reference(30, 1), USN_REASON_RENAME_NEW_NAME, "b.txt")).unwrap();
+            assert_eq!((record.usn, record.file_reference, record.parent), (4096, reference(31, 2), reference(30, 1)));
+            assert_eq!((record.reason, record.name.as_str()), (USN_REASON_RENAME_NEW_NAME, "b.txt"));
+            assert_eq!(record.timestamp, FileTime(0x01DA_0000_0000_0000));
+        }
+
+        let mut v4 = usn_record(3, 0, reference(31, 2), reference(30, 1), 0, "");
+        v4[4] = 4;
+        assert!(UsnRecord::parse(&v4).is_err());
+        let record = usn_record(2, 0, reference(31, 2), reference(30, 1), 0, "name");
+        assert!(UsnRecord::parse(&record[..0x3C]).is_err());
+        assert!(UsnRecord::parse(&record[..0x20]).is_err());
+    }
+
+    // `basic_volume` with $Extend\$UsnJrnl (28), whose first page has been freed. After the changes, the second
+    // page logs a.txt being renamed to b.txt and new.txt (29) being created in docs.
+    fn journal_volume(changed: bool) -> TestVolume {
+        let mut v = basic_volume();
+        let name = |parent, n| resident(ATTR_FILE_NAME, "", &file_name(parent, n, NAMESPACE_WIN32));
+        v.set_record(11, record(11, IN_USE | DIRECTORY, FileReference::default(), &[name(reference(5, 5), "$Extend")]));
+
+        let mut max = vec![0u8; 0x20];
+        max[0x10..0x18].copy_from_slice(&JOURNAL_ID.to_le_bytes());
+        max[0x18..0x20].copy_from_slice(&4096u64.to_le_bytes());
+        let journal_size = if changed { 8192 } else { 4096 };
+        v.set_record(28, record(1, IN_USE, FileReference::default(), &[
+            name(reference(11, 11), USN_JOURNAL_NAME),
+            resident(ATTR_DATA, USN_JOURNAL_MAX_STREAM, &max),
+            non_resident(&NonResident::new(ATTR_DATA, USN_JOURNAL_DATA_STREAM, vec![(1, None), (1, Some(50))], journal_size)),
+        ]));
+
+        if changed {
+            let rename = usn_record(2, 4096, reference(31, 2), reference(30, 1), USN_REASON_RENAME_NEW_NAME, "b.txt");
+            let create = usn_record(3, 4096 + rename.len() as u64, reference(29, 1), reference(30, 1), USN_REASON_FILE_CREATE, "new.txt");
+            v.write(50, &[rename, create].concat());
+            v.set_record(31, record(2, IN_USE, FileReference::default(), &[name(reference(30, 1), "b.txt"), resident(ATTR_DATA, "", b"hello world")]));
+            v.set_record(29, record(1, IN_USE, FileReference::default(), &[name(reference(30, 1), "new.txt")]));
+        }
+        v
+    }
+
+    fn names(index: &VolumeIndexFlatArray) -> Vec<Option<String>> {
+        index.0.iter().map(|f| f.as_ref().and_then(|f| f.name()).map(String::from)).collect()
+    }
+
+    #[test]
+    fn update_from_the_journal() {
+        let mut before = Cursor::new(journal_volume(false).finish());
+        let mut index = VolumeIndexFlatArray::from_volume_reader(&mut before, None).unwrap();
+        let since = index.journal_position(&mut before).unwrap().unwrap();
+        assert_eq!(since, JournalPosition { journal_id: JOURNAL_ID, next_usn: 4096 });
+
+        let mut after = Cursor::new(journal_volume(true).finish());
+        let layout = MftLayout::read(&mut after).unwrap();
+        let journal = UsnJournal::open(&mut after, &layout, &index).unwrap().unwrap();
+        let records = journal.read_since(&mut after, since).unwrap().unwrap();
+        assert_eq!(records.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), ["b.txt", "new.txt"]);
+        assert_eq!(records[1].usn, 4096 + 0x48);
+
+        let position = index.update_from_journal(&mut after, since).unwrap();
+        assert_eq!(position, Some(JournalPosition { journal_id: JOURNAL_ID, next_usn: 8192 }));
+        let rescanned = VolumeIndexFlatArray::from_volume_reader(&mut after, None).unwrap();
+        assert_eq!(names(&index), names(&rescanned));
+        assert_eq!(index.0[29].as_ref().unwrap().links[0].parent, reference(30, 1));
+    }
+
+    #[test]
+    fn positions_the_journal_no_longer_has() {
+        let mut volume = Cursor::new(journal_volume(true).finish());
+        let layout = MftLayout::read(&mut volume).unwrap();
+        let index = VolumeIndexFlatArray::from_volume_reader(&mut volume, None).unwrap();
+        let journal = UsnJournal::open(&mut volume, &layout, &index).unwrap().unwrap();
+        assert_eq!(journal.position_in(&index), Some(journal.position()));
+
+        for since in [
+            JournalPosition { journal_id: JOURNAL_ID + 1, next_usn: 4096 },
+            JournalPosition { journal_id: JOURNAL_ID, next_usn: 0 },
+            JournalPosition { journal_id: JOURNAL_ID, next_usn: 8200 },
+        ] {
+            assert!(journal.read_since(&mut volume, since).unwrap().is_none());
+        }
+        assert!(journal.read_since(&mut volume, journal.position()).unwrap().unwrap().is_empty());
+
+        let mut index = VolumeIndexFlatArray::from_volume_reader(&mut Cursor::new(basic_volume().finish()), None).unwrap();
+        let since = JournalPosition { journal_id: JOURNAL_ID, next_usn: 4096 };
+        assert_eq!(index.update_from_journal(&mut Cursor::new(basic_volume().finish()), since).unwrap(), None);
+    }
+}
@@ -0,0 +1,619 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use anyhow::{bail, Result};
+use crate::bytes::{be_u32, be_u64, le_u16, le_u32, le_u64};
+use crate::volume::{SectorDevice, SectorReader};
+
+// The disk inside a Hyper-V VHD or VHDX file, read through the usual sector buffering
+pub type VhdReader = SectorReader<VhdDisk>;
+pub type VhdxReader = SectorReader<VhdxDisk>;
+
+const VHD_SECTOR_SIZE: u64 = 512;
+const VHD_FOOTER_COOKIE: &[u8] = b"conectix";
+const VHD_DYNAMIC_HEADER_COOKIE: &[u8] = b"cxsparse";
+const VHD_DISK_TYPE_FIXED: u32 = 2;
+const VHD_DISK_TYPE_DYNAMIC: u32 = 3;
+const VHD_DISK_TYPE_DIFFERENCING: u32 = 4;
+const VHD_UNALLOCATED_BLOCK: u32 = 0xFFFFFFFF;
+
+pub enum VhdLayout {
+    // The disk is stored as-is, followed by the footer
+    Fixed,
+    // The disk is split into blocks that are only stored once written, located through the BAT
+    Dynamic {
+        block_size: u64,
+        // Every stored block starts with a sector bitmap padded to a whole number of sectors
+        bitmap_size: u64,
+        // Sector offset of each block in the file, or VHD_UNALLOCATED_BLOCK
+        bat: Vec<u32>,
+    },
+}
+
+pub struct VhdDisk {
+    pub file: File,
+    pub disk_size: u64,
+    pub layout: VhdLayout,
+}
+
+impl VhdDisk {
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+
+        // All VHDs end with a footer
+        let mut footer = [0u8; 512];
+        file.seek(SeekFrom::End(-(footer.len() as i64)))?;
+        file.read_exact(&mut footer)?;
+        if &footer[0..8] != VHD_FOOTER_COOKIE {
+            bail!("\"{}\" is not a VHD: footer cookie not found", path);
+        }
+
+        let data_offset = be_u64(&footer, 16);
+        let disk_size = be_u64(&footer, 48);
+        let layout = match be_u32(&footer, 60) {
+            VHD_DISK_TYPE_FIXED => VhdLayout::Fixed,
+            VHD_DISK_TYPE_DYNAMIC => {
+                let mut header = [0u8; 1024];
+                file.seek(SeekFrom::Start(data_offset))?;
+                file.read_exact(&mut header)?;
+                if &header[0..8] != VHD_DYNAMIC_HEADER_COOKIE {
+                    bail!("\"{}\" is corrupt: dynamic disk header not found", path);
+                }
+
+                let table_offset = be_u64(&header, 16);
+                let max_table_entries = be_u32(&header, 28) as u64;
+                let block_size = be_u32(&header, 32) as u64;
+                if block_size < VHD_SECTOR_SIZE || !block_size.is_power_of_two() {
+                    bail!("\"{}\" is corrupt: invalid block size {}", path, block_size);
+                }
+
+                let sectors_per_block = block_size / VHD_SECTOR_SIZE;
+                let bitmap_size = sectors_per_block.div_ceil(8).next_multiple_of(VHD_SECTOR_SIZE);
+
+                // Entries past the end of the disk are never looked up
+                let table_entries = max_table_entries.min(disk_size.div_ceil(block_size));
+                check_in_file(path, "block allocation table", table_offset, table_entries * 4, file_length)?;
+                let mut table = vec![0u8; table_entries as usize * 4];
+                file.seek(SeekFrom::Start(table_offset))?;
+                file.read_exact(&mut table)?;
+                let bat = (0..table.len() / 4).map(|i| be_u32(&table, i * 4)).collect();
+
+                VhdLayout::Dynamic { block_size, bitmap_size, bat }
+            }
+            VHD_DISK_TYPE_DIFFERENCING => bail!("\"{}\" is a differencing VHD, which needs its parent disk and isn't supported", path),
+            disk_type => bail!("\"{}\" has unknown VHD disk type {}", path, disk_type),
+        };
+
+        Ok(VhdDisk { file, disk_size, layout })
+    }
+}
+
+// Fails if a table read from the file would extend past its end, so that a corrupt length can't size a huge allocation
+fn check_in_file(path: &str, what: &str, offset: u64, length: u64, file_length: u64) -> Result<()> {
+    if offset.checked_add(length).is_none_or(|end| end > file_length) {
+        bail!("\"{}\" is corrupt: {} extends past the end of the file", path, what);
+    }
+    Ok(())
+}
+
+impl SectorDevice for VhdDisk {
+    fn sector_size(&self) -> u32 {
+        VHD_SECTOR_SIZE as u32
+    }
+
+    fn length(&self) -> u64 {
+        self.disk_size
+    }
+
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.disk_size.saturating_sub(offset) as usize);
+
+        match &self.layout {
+            VhdLayout::Fixed => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut buf[..len])?;
+            }
+            VhdLayout::Dynamic { block_size, bitmap_size, bat } => {
+                let mut filled = 0;
+                while filled < len {
+                    let pos = offset + filled as u64;
+                    let block_offset = pos % block_size;
+                    let n = (len - filled).min((block_size - block_offset) as usize);
+
+                    match bat.get((pos / block_size) as usize) {
+                        Some(&sector) if sector != VHD_UNALLOCATED_BLOCK => {
+                            let file_offset = sector as u64 * VHD_SECTOR_SIZE + bitmap_size + block_offset;
+                            self.file.seek(SeekFrom::Start(file_offset))?;
+                            self.file.read_exact(&mut buf[filled..filled + n])?;
+                        }
+                        // Blocks that were never written read as zeros
+                        _ => buf[filled..filled + n].fill(0),
+                    }
+
+                    filled += n;
+                }
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+impl VhdReader {
+    pub fn open_path(path: &str) -> Result<Self> {
+        Ok(SectorReader::new(VhdDisk::open_path(path)?))
+    }
+}
+
+const VHDX_FILE_SIGNATURE: &[u8] = b"vhdxfile";
+const VHDX_HEADER_SIGNATURE: &[u8] = b"head";
+const VHDX_REGION_TABLE_SIGNATURE: &[u8] = b"regi";
+const VHDX_METADATA_TABLE_SIGNATURE: &[u8] = b"metadata";
+const VHDX_HEADER_OFFSETS: [u64; 2] = [0x10000, 0x20000];
+const VHDX_HEADER_SIZE: usize = 0x1000;
+const VHDX_REGION_TABLE_OFFSETS: [u64; 2] = [0x30000, 0x40000];
+const VHDX_REGION_TABLE_SIZE: usize = 0x10000;
+const VHDX_MB: u64 = 1024 * 1024;
+
+// GUIDs as they are laid out on disk (the first three fields are little endian)
+const VHDX_BAT_REGION: [u8; 16] = [0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08];
+const VHDX_METADATA_REGION: [u8; 16] = [0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E];
+const VHDX_FILE_PARAMETERS: [u8; 16] = [0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B];
+const VHDX_VIRTUAL_DISK_SIZE: [u8; 16] = [0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8];
+const VHDX_LOGICAL_SECTOR_SIZE: [u8; 16] = [0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F];
+
+const VHDX_HAS_PARENT: u32 = 0x2;
+
+// Payload block states stored in the low 3 bits of a BAT entry
+const VHDX_PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const VHDX_PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+pub struct VhdxDisk {
+    pub file: File,
+    pub disk_size: u64,
+    pub block_size: u64,
+    pub logical_sector_size: u32,
+    // Number of payload blocks described by one sector bitmap block; the BAT has a bitmap entry after each chunk
+    pub chunk_ratio: u64,
+    pub bat: Vec<u64>,
+}
+
+impl VhdxDisk {
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+
+        let mut signature = [0u8; 8];
+        file.read_exact(&mut signature)?;
+        if signature != VHDX_FILE_SIGNATURE {
+            bail!("\"{}\" is not a VHDX: file signature not found", path);
+        }
+
+        // Of the two headers, the valid one with the highest sequence number is current
+        let mut current_header = None::<(u64, Vec<u8>)>;
+        for offset in VHDX_HEADER_OFFSETS {
+            let header = read_vhdx_structure(&mut file, offset, VHDX_HEADER_SIZE, VHDX_HEADER_SIGNATURE)?;
+            if let Some(header) = header {
+                let sequence_number = le_u64(&header, 8);
+                if current_header.as_ref().is_none_or(|(s, _)| sequence_number > *s) {
+                    current_header = Some((sequence_number, header));
+                }
+            }
+        }
+        let header = match current_header {
+            Some((_, header)) => header,
+            None => bail!("\"{}\" is corrupt: no valid VHDX header", path),
+        };
+
+        // A non-empty log GUID means there are writes in the log that haven't been applied to the file yet
+        if header[48..64].iter().any(|b| *b != 0) {
+            bail!("\"{}\" has a pending VHDX log; attach it once in Windows to replay the log before scanning", path);
+        }
+
+        let mut region_table = None;
+        for offset in VHDX_REGION_TABLE_OFFSETS {
+            region_table = read_vhdx_structure(&mut file, offset, VHDX_REGION_TABLE_SIZE, VHDX_REGION_TABLE_SIGNATURE)?;
+            if region_table.is_some() {
+                break;
+            }
+        }
+        let region_table = match region_table {
+            Some(table) => table,
+            None => bail!("\"{}\" is corrupt: no valid VHDX region table", path),
+        };
+
+        let mut bat_region = None::<(u64, u32)>;
+        let mut metadata_region = None::<(u64, u32)>;
+        let region_count = (le_u32(&region_table, 8) as usize).min((VHDX_REGION_TABLE_SIZE - 16) / 32);
+        for i in 0..region_count {
+            let entry = &region_table[16 + i * 32..16 + (i + 1) * 32];
+            let region = Some((le_u64(entry, 16), le_u32(entry, 24)));
+            if entry[0..16] == VHDX_BAT_REGION {
+                bat_region = region;
+            } else if entry[0..16] == VHDX_METADATA_REGION {
+                metadata_region = region;
+            } else if le_u32(entry, 28) & 1 != 0 {
+                bail!("\"{}\" requires an unknown VHDX region", path);
+            }
+        }
+        let (Some((bat_offset, bat_length)), Some((metadata_offset, metadata_length))) = (bat_region, metadata_region) else {
+            bail!("\"{}\" is corrupt: missing BAT or metadata region", path);
+        };
+
+        check_in_file(path, "metadata region", metadata_offset, metadata_length as u64, file_length)?;
+        let mut metadata = vec![0u8; metadata_length as usize];
+        file.seek(SeekFrom::Start(metadata_offset))?;
+        file.read_exact(&mut metadata)?;
+        if metadata.len() < 32 || &metadata[0..8] != VHDX_METADATA_TABLE_SIGNATURE {
+            bail!("\"{}\" is corrupt: metadata table not found", path);
+        }
+
+        let mut block_size = None;
+        let mut has_parent = false;
+        let mut disk_size = None;
+        let mut logical_sector_size = None;
+        let item_count = le_u16(&metadata, 10) as usize;
+        for i in 0..item_count {
+            let entry_offset = 32 + i * 32;
+            if entry_offset + 32 > metadata.len() {
+                break;
+            }
+            let entry = &metadata[entry_offset..entry_offset + 32];
+            let item_offset = le_u32(entry, 16) as usize;
+            let item_length = le_u32(entry, 20) as usize;
+            if item_offset + item_length > metadata.len() {
+                bail!("\"{}\" is corrupt: metadata item out of bounds", path);
+            }
+
+            let item = &metadata[item_offset..item_offset + item_length];
+            if entry[0..16] == VHDX_FILE_PARAMETERS && item.len() >= 8 {
+                block_size = Some(le_u32(item, 0) as u64);
+                has_parent = le_u32(item, 4) & VHDX_HAS_PARENT != 0;
+            } else if entry[0..16] == VHDX_VIRTUAL_DISK_SIZE && item.len() >= 8 {
+                disk_size = Some(le_u64(item, 0));
+            } else if entry[0..16] == VHDX_LOGICAL_SECTOR_SIZE && item.len() >= 4 {
+                logical_sector_size = Some(le_u32(item, 0));
+            }
+        }
+        let (Some(block_size), Some(disk_size), Some(logical_sector_size)) = (block_size, disk_size, logical_sector_size) else {
+            bail!("\"{}\" is corrupt: missing required metadata items", path);
+        };
+        if has_parent {
+            bail!("\"{}\" is a differencing VHDX, which needs its parent disk and isn't supported", path);
+        }
+        if !block_size.is_power_of_two() || !(VHDX_MB..=256 * VHDX_MB).contains(&block_size) {
+            bail!("\"{}\" is corrupt: invalid block size {}", path, block_size);
+        }
+        if logical_sector_size != 512 && logical_sector_size != 4096 {
+            bail!("\"{}\" is corrupt: invalid logical sector size {}", path, logical_sector_size);
+        }
+        let chunk_ratio = (1u64 << 23) * logical_sector_size as u64 / block_size;
+
+        // Only the entries up to the last payload block are needed, however large the region claims to be
+        check_in_file(path, "BAT region", bat_offset, bat_length as u64, file_length)?;
+        let payload_blocks = disk_size.div_ceil(block_size);
+        let bat_entries = payload_blocks + payload_blocks.saturating_sub(1) / chunk_ratio;
+        let mut table = vec![0u8; (bat_length as u64).min(bat_entries.saturating_mul(8)) as usize];
+        file.seek(SeekFrom::Start(bat_offset))?;
+        file.read_exact(&mut table)?;
+        let bat = (0..table.len() / 8).map(|i| le_u64(&table, i * 8)).collect();
+
+        Ok(VhdxDisk {
+            file,
+            disk_size,
+            block_size,
+            logical_sector_size,
+            chunk_ratio,
+            bat,
+        })
+    }
+}
+
+// Reads a checksummed VHDX structure, returning None if its signature or CRC-32C doesn't match
+fn read_vhdx_structure(file: &mut File, offset: u64, size: usize, signature: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut buf = vec![0u8; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+
+    if &buf[0..4] != signature {
+        return Ok(None);
+    }
+
+    // The checksum is computed with the checksum field itself zeroed
+    let checksum = le_u32(&buf, 4);
+    buf[4..8].fill(0);
+    if crc32c(&buf) != checksum {
+        return Ok(None);
+    }
+
+    Ok(Some(buf))
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F63B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl SectorDevice for VhdxDisk {
+    fn sector_size(&self) -> u32 {
+        self.logical_sector_size
+    }
+
+    fn length(&self) -> u64 {
+        self.disk_size
+    }
+
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.disk_size.saturating_sub(offset) as usize);
+
+        let mut filled = 0;
+        while filled < len {
+            let pos = offset + filled as u64;
+            let block = pos / self.block_size;
+            let block_offset = pos % self.block_size;
+            let n = (len - filled).min((self.block_size - block_offset) as usize);
+
+            // Skip over the sector bitmap entries interleaved after every chunk of payload entries
+            let entry = self.bat.get((block + block / self.chunk_ratio) as usize).copied().unwrap_or(0);
+            match entry & 0x7 {
+                VHDX_PAYLOAD_BLOCK_FULLY_PRESENT | VHDX_PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
+                    let file_offset = (entry >> 20) * VHDX_MB + block_offset;
+                    self.file.seek(SeekFrom::Start(file_offset))?;
+                    self.file.read_exact(&mut buf[filled..filled + n])?;
+                }
+                // Not present, zero, unmapped and undefined blocks all read as zeros without a parent disk
+                _ => buf[filled..filled + n].fill(0),
+            }
+
+            filled += n;
+        }
+
+        Ok(len)
+    }
+}
+
+impl VhdxReader {
+    pub fn open_path(path: &str) -> Result<Self> {
+        Ok(SectorReader::new(VhdxDisk::open_path(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const MB: usize = VHDX_MB as usize;
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("win_dedupe_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn vhd_footer(disk_type: u32, data_offset: u64, disk_size: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; 512];
+        footer[0..8].copy_from_slice(VHD_FOOTER_COOKIE);
+        footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+        footer[48..56].copy_from_slice(&disk_size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        footer
+    }
+
+    // Two 4 KB blocks, of which only the first is stored
+    fn dynamic_vhd(block_size: u32) -> Vec<u8> {
+        let footer = vhd_footer(VHD_DISK_TYPE_DYNAMIC, 512, 8192);
+        let mut file = footer.clone();
+
+        let mut header = vec![0u8; 1024];
+        header[0..8].copy_from_slice(VHD_DYNAMIC_HEADER_COOKIE);
+        header[16..24].copy_from_slice(&1536u64.to_be_bytes());
+        header[28..32].copy_from_slice(&2u32.to_be_bytes());
+        header[32..36].copy_from_slice(&block_size.to_be_bytes());
+        file.extend_from_slice(&header);
+
+        let mut bat = vec![0u8; 512];
+        bat[0..4].copy_from_slice(&4u32.to_be_bytes());
+        bat[4..8].copy_from_slice(&VHD_UNALLOCATED_BLOCK.to_be_bytes());
+        file.extend_from_slice(&bat);
+
+        // One sector of bitmap, then the block
+        file.extend_from_slice(&[0xFF; 512]);
+        file.extend((0..4096).map(|i| (i % 251) as u8));
+        file.extend_from_slice(&footer);
+        file
+    }
+
+    #[test]
+    fn fixed_vhd() {
+        let mut contents: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        contents.extend_from_slice(&vhd_footer(VHD_DISK_TYPE_FIXED, u64::MAX, 4096));
+        let path = temp_file("fixed.vhd", &contents);
+
+        let mut disk = VhdDisk::open_path(path.to_str().unwrap()).unwrap();
+        assert_eq!(disk.length(), 4096);
+        let mut buf = vec![0u8; 8192];
+        assert_eq!(disk.read_sectors(0, &mut buf).unwrap(), 4096);
+        assert_eq!(buf[..4096], contents[..4096]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dynamic_vhd_blocks() {
+        let path = temp_file("dynamic.vhd", &dynamic_vhd(4096));
+
+        let mut disk = VhdDisk::open_path(path.to_str().unwrap()).unwrap();
+        let mut buf = vec![0xAAu8; 8192];
+        assert_eq!(disk.read_sectors(0, &mut buf).unwrap(), 8192);
+        assert!(buf[..4096].iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+        assert!(buf[4096..].iter().all(|b| *b == 0));
+
+        // Reads that start partway into a block
+        let mut buf = vec![0u8; 1024];
+        disk.read_sectors(3584, &mut buf).unwrap();
+        assert!(buf[..512].iter().enumerate().all(|(i, b)| *b == ((i + 3584) % 251) as u8));
+        assert!(buf[512..].iter().all(|b| *b == 0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dynamic_vhd_invalid_block_size() {
+        for block_size in [0, 256, 3000] {
+            let path = temp_file(&format!("block_size_{}.vhd", block_size), &dynamic_vhd(block_size));
+            assert!(VhdDisk::open_path(path.to_str().unwrap()).is_err());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn dynamic_vhd_table_past_end() {
+        // A huge entry count is capped by the disk size, and a huge disk by the file length
+        let mut file = dynamic_vhd(4096);
+        file[512 + 28..512 + 32].copy_from_slice(&u32::MAX.to_be_bytes());
+        let path = temp_file("huge_table.vhd", &file);
+        assert_eq!(VhdDisk::open_path(path.to_str().unwrap()).unwrap().length(), 8192);
+
+        let footer_offset = file.len() - 512;
+        file[footer_offset + 48..footer_offset + 56].copy_from_slice(&(1u64 << 40).to_be_bytes());
+        std::fs::write(&path, &file).unwrap();
+        assert!(VhdDisk::open_path(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn not_a_vhd() {
+        let path = temp_file("not_a.vhd", &[0u8; 1024]);
+        assert!(VhdDisk::open_path(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn put_vhdx_structure(file: &mut [u8], offset: usize, structure: &mut [u8]) {
+        let checksum = crc32c(structure);
+        structure[4..8].copy_from_slice(&checksum.to_le_bytes());
+        file[offset..offset + structure.len()].copy_from_slice(structure);
+    }
+
+    // A 2 MB disk of 1 MB blocks, of which only the first is stored, at 3 MB into the file
+    fn vhdx() -> Vec<u8> {
+        let mut file = vec![0u8; 4 * MB];
+        file[0..8].copy_from_slice(VHDX_FILE_SIGNATURE);
+
+        let mut header = vec![0u8; VHDX_HEADER_SIZE];
+        header[0..4].copy_from_slice(VHDX_HEADER_SIGNATURE);
+        header[8..16].copy_from_slice(&1u64.to_le_bytes());
+        put_vhdx_structure(&mut file, VHDX_HEADER_OFFSETS[0] as usize, &mut header);
+        // A newer header that fails its checksum is ignored
+        header[8..16].copy_from_slice(&2u64.to_le_bytes());
+        header[48] = 1;
+        put_vhdx_structure(&mut file, VHDX_HEADER_OFFSETS[1] as usize, &mut header);
+        file[VHDX_HEADER_OFFSETS[1] as usize + 100] ^= 1;
+
+        let mut regions = vec![0u8; VHDX_REGION_TABLE_SIZE];
+        regions[0..4].copy_from_slice(VHDX_REGION_TABLE_SIGNATURE);
+        regions[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (i, (guid, offset)) in [(VHDX_METADATA_REGION, MB), (VHDX_BAT_REGION, 2 * MB)].into_iter().enumerate() {
+            let entry = &mut regions[16 + i * 32..16 + (i + 1) * 32];
+            entry[0..16].copy_from_slice(&guid);
+            entry[16..24].copy_from_slice(&(offset as u64).to_le_bytes());
+            entry[24..28].copy_from_slice(&(MB as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+        }
+        put_vhdx_structure(&mut file, VHDX_REGION_TABLE_OFFSETS[0] as usize, &mut regions);
+
+        let metadata = &mut file[MB..2 * MB];
+        metadata[0..8].copy_from_slice(VHDX_METADATA_TABLE_SIGNATURE);
+        metadata[10..12].copy_from_slice(&3u16.to_le_bytes());
+        let items: [([u8; 16], Vec<u8>); 3] = [
+            (VHDX_FILE_PARAMETERS, [(MB as u32).to_le_bytes(), 0u32.to_le_bytes()].concat()),
+            (VHDX_VIRTUAL_DISK_SIZE, (2 * MB as u64).to_le_bytes().to_vec()),
+            (VHDX_LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec()),
+        ];
+        for (i, (guid, item)) in items.iter().enumerate() {
+            let item_offset = 0x10000 + i * 0x100;
+            let entry = &mut metadata[32 + i * 32..32 + (i + 1) * 32];
+            entry[0..16].copy_from_slice(guid);
+            entry[16..20].copy_from_slice(&(item_offset as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(item.len() as u32).to_le_bytes());
+            metadata[item_offset..item_offset + item.len()].copy_from_slice(item);
+        }
+
+        let bat = &mut file[2 * MB..];
+        bat[0..8].copy_from_slice(&((3u64 << 20) | VHDX_PAYLOAD_BLOCK_FULLY_PRESENT).to_le_bytes());
+        for (i, b) in file[3 * MB..].iter_mut().enumerate() {
+            *b = (i % 253) as u8;
+        }
+        file
+    }
+
+    #[test]
+    fn vhdx_blocks() {
+        let path = temp_file("disk.vhdx", &vhdx());
+
+        let mut disk = VhdxDisk::open_path(path.to_str().unwrap()).unwrap();
+        assert_eq!(disk.length(), 2 * MB as u64);
+        assert_eq!(disk.sector_size(), 512);
+        assert_eq!(disk.chunk_ratio, 4096);
+        assert_eq!(disk.bat.len(), 2);
+
+        let mut buf = vec![0xAAu8; 2 * MB];
+        assert_eq!(disk.read_sectors(0, &mut buf).unwrap(), 2 * MB);
+        assert!(buf[..MB].iter().enumerate().all(|(i, b)| *b == (i % 253) as u8));
+        assert!(buf[MB..].iter().all(|b| *b == 0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vhdx_pending_log() {
+        let mut file = vhdx();
+        let mut header = file[VHDX_HEADER_OFFSETS[0] as usize..][..VHDX_HEADER_SIZE].to_vec();
+        header[48] = 1;
+        put_vhdx_structure(&mut file, VHDX_HEADER_OFFSETS[0] as usize, &mut header);
+        let path = temp_file("log.vhdx", &file);
+
+        assert!(VhdxDisk::open_path(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vhdx_invalid_block_or_sector_size() {
+        let file_parameters = MB + 0x10000;
+        let logical_sector_size = MB + 0x10200;
+        for (offset, value) in [
+            (file_parameters, 0),
+            (file_parameters, 1536 * 1024),
+            (file_parameters, 512 * MB as u32),
+            (logical_sector_size, 0),
+            (logical_sector_size, 1024),
+        ] {
+            let mut file = vhdx();
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            let path = temp_file(&format!("invalid_{}_{}.vhdx", offset, value), &file);
+            assert!(VhdxDisk::open_path(path.to_str().unwrap()).is_err());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn vhdx_region_past_end() {
+        let mut file = vhdx();
+        let mut regions = file[VHDX_REGION_TABLE_OFFSETS[0] as usize..][..VHDX_REGION_TABLE_SIZE].to_vec();
+        // The BAT region is the second entry
+        regions[48 + 24..48 + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+        put_vhdx_structure(&mut file, VHDX_REGION_TABLE_OFFSETS[0] as usize, &mut regions);
+        let path = temp_file("region.vhdx", &file);
+
+        assert!(VhdxDisk::open_path(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE3069283);
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use anyhow::Result;
+use crate::image::{segment_number, ImageReader, SegmentedImageReader};
+use crate::vhd::{VhdReader, VhdxReader};
+
+// A seekable view of an NTFS volume, regardless of what the bytes are actually stored on.
+// Everything from `MftLayout::read` to `VolumeIndexFlatArray::from_volume_reader` only needs this.
+// Reads past `length()` return short, like reading past the end of a file.
+pub trait VolumeSource: Read + Seek {
+    fn sector_size(&self) -> u32;
+    fn length(&self) -> u64;
+
+    // Hints that the byte ranges in `extents` are about to be read in order, so they can be fetched in the background
+    fn read_ahead(&mut self, _extents: &[Range<u64>]) {}
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+// Raw storage that, like a Win32 volume handle, can only read whole sectors at sector aligned offsets.
+// Returns how many bytes were actually read, which may be short at the end of the device.
+// Devices are shared with the read-ahead thread, hence Send.
+pub trait SectorDevice: Send + 'static {
+    fn sector_size(&self) -> u32;
+    fn length(&self) -> u64;
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+// Blocks are a multiple of every sector size we support, so they're always sector aligned
+pub const CACHE_BLOCK_SIZE: usize = 2usize.pow(20); // 1 MB
+pub const CACHE_BLOCK_COUNT: usize = 64;
+// How many blocks the read-ahead thread may get ahead of the reader
+const READ_AHEAD_QUEUE_BLOCKS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Blocks fetched in the background, and how many of those were used before being evicted
+    pub read_ahead_blocks: u64,
+    pub read_ahead_hits: u64,
+    pub evictions: u64,
+}
+
+struct CacheBlock {
+    data: Vec<u8>,
+    last_used: u64,
+    from_read_ahead: bool,
+}
+
+struct ReadAhead {
+    blocks: Receiver<(u64, Vec<u8>)>,
+    // Blocks the read-ahead thread has yet to deliver
+    pending: HashSet<u64>,
+}
+
+// Turns a sector device into a byte addressable reader with an LRU cache of whole blocks
+pub struct SectorReader<D: SectorDevice> {
+    pub device: Arc<Mutex<D>>,
+    pub virtual_file_ptr: i64,
+    sector_size: u32,
+    length: u64,
+    cache: HashMap<u64, CacheBlock>,
+    use_counter: u64,
+    read_ahead: Option<ReadAhead>,
+    stats: CacheStats,
+}
+
+impl<D: SectorDevice> SectorReader<D> {
+    pub fn new(device: D) -> Self {
+        SectorReader {
+            sector_size: device.sector_size(),
+            length: device.length(),
+            device: Arc::new(Mutex::new(device)),
+            virtual_file_ptr: 0,
+            cache: HashMap::new(),
+            use_counter: 0,
+            read_ahead: None,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    // Starts a background thread reading the blocks covering `extents`, in order. Replaces any previous read-ahead.
+    pub fn read_ahead(&mut self, extents: &[Range<u64>]) {
+        let block_size = CACHE_BLOCK_SIZE as u64;
+        let mut blocks = Vec::new();
+        let mut pending = HashSet::new();
+        for extent in extents {
+            let end = extent.end.min(self.length);
+            for block in extent.start / block_size..end.div_ceil(block_size) {
+                if !self.cache.contains_key(&block) && pending.insert(block) {
+                    blocks.push((block, self.block_read_size(block)));
+                }
+            }
+        }
+
+        // Dropping the previous receiver makes the previous thread stop at its next block
+        let (sender, receiver) = sync_channel(READ_AHEAD_QUEUE_BLOCKS);
+        let device = self.device.clone();
+        thread::spawn(move || {
+            for (block, read_size) in blocks {
+                let mut data = vec![0u8; read_size];
+                // On error, leave the block for the reader to fetch itself so it sees the error
+                match device.lock().unwrap().read_sectors(block * block_size, &mut data) {
+                    Ok(n) => data.truncate(n),
+                    Err(_) => break,
+                }
+                if sender.send((block, data)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.read_ahead = Some(ReadAhead { blocks: receiver, pending });
+    }
+
+    // Moves finished read-ahead blocks into the cache, waiting for `wanted` if the read-ahead thread will deliver it
+    fn receive_read_ahead(&mut self, wanted: u64) {
+        loop {
+            let Some(read_ahead) = &mut self.read_ahead else {
+                return;
+            };
+
+            let received = if read_ahead.pending.contains(&wanted) {
+                read_ahead.blocks.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                read_ahead.blocks.try_recv()
+            };
+
+            match received {
+                Ok((block, data)) => {
+                    read_ahead.pending.remove(&block);
+                    self.stats.read_ahead_blocks += 1;
+                    self.insert_block(block, data, true);
+                    // Stop at the wanted block, so blocks arriving after it can't evict it before it's used
+                    if block == wanted {
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.read_ahead = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn insert_block(&mut self, block: u64, data: Vec<u8>, from_read_ahead: bool) {
+        if self.cache.len() >= CACHE_BLOCK_COUNT && !self.cache.contains_key(&block) {
+            let lru = self.cache.iter().min_by_key(|(_, b)| b.last_used).map(|(n, _)| *n).unwrap();
+            self.cache.remove(&lru);
+            self.stats.evictions += 1;
+        }
+
+        self.use_counter += 1;
+        self.cache.insert(block, CacheBlock {
+            data,
+            last_used: self.use_counter,
+            from_read_ahead,
+        });
+    }
+
+    // Bytes of `block` that lie within the volume, rounded up to whole sectors for the device
+    fn block_read_size(&self, block: u64) -> usize {
+        let remaining = self.length.saturating_sub(block * CACHE_BLOCK_SIZE as u64);
+        remaining.next_multiple_of(self.sector_size as u64).min(CACHE_BLOCK_SIZE as u64) as usize
+    }
+
+    // Returns the cached contents of `block`, which is shorter than CACHE_BLOCK_SIZE at the end of the volume
+    fn block(&mut self, block: u64) -> io::Result<&[u8]> {
+        self.receive_read_ahead(block);
+
+        if self.cache.contains_key(&block) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            let mut data = vec![0u8; self.block_read_size(block)];
+            let n = self.device.lock().unwrap().read_sectors(block * CACHE_BLOCK_SIZE as u64, &mut data)?;
+            data.truncate(n);
+            self.insert_block(block, data, false);
+        }
+
+        self.use_counter += 1;
+        let cached = self.cache.get_mut(&block).unwrap();
+        cached.last_used = self.use_counter;
+        if cached.from_read_ahead {
+            cached.from_read_ahead = false;
+            self.stats.read_ahead_hits += 1;
+        }
+
+        Ok(&cached.data)
+    }
+}
+
+impl<D: SectorDevice> Read for SectorReader<D> {
+    // Fills as much of `buf` as the volume allows, only returning a short read at the end of the volume
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = CACHE_BLOCK_SIZE as u64;
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            let pos = self.virtual_file_ptr as u64;
+            if pos >= self.length {
+                break;
+            }
+
+            let block = pos / block_size;
+            let block_offset = (pos % block_size) as usize;
+            let remaining = &mut buf[filled..];
+
+            // A block the read-ahead thread has queued or is reading is waited for rather than read twice
+            self.receive_read_ahead(block);
+
+            // Whole uncached blocks go straight into the caller's buffer instead of through the cache
+            let n = if block_offset == 0 && remaining.len() >= CACHE_BLOCK_SIZE && !self.cache.contains_key(&block) {
+                let read_size = self.block_read_size(block).min(remaining.len());
+                self.stats.misses += 1;
+                self.device.lock().unwrap().read_sectors(pos, &mut remaining[..read_size])?
+            } else {
+                let data = self.block(block)?;
+                let n = remaining.len().min(data.len().saturating_sub(block_offset));
+                remaining[..n].copy_from_slice(&data[block_offset..block_offset + n]);
+                n
+            };
+
+            // Never hand out bytes past the end of the volume, even if the device has them
+            let n = n.min((self.length - pos) as usize);
+            if n == 0 {
+                break;
+            }
+
+            filled += n;
+            self.virtual_file_ptr += n as i64;
+        }
+
+        Ok(filled)
+    }
+}
+
+impl<D: SectorDevice> Seek for SectorReader<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_file_ptr = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).ok(),
+            SeekFrom::End(offset) => (self.length as i64).checked_add(offset),
+            SeekFrom::Current(offset) => self.virtual_file_ptr.checked_add(offset),
+        };
+
+        match new_file_ptr {
+            Some(p) if p >= 0 => {
+                self.virtual_file_ptr = p;
+                Ok(p as u64)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative or overflowing position")),
+        }
+    }
+}
+
+impl<D: SectorDevice> VolumeSource for SectorReader<D> {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_ahead(&mut self, extents: &[Range<u64>]) {
+        SectorReader::read_ahead(self, extents)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(SectorReader::cache_stats(self))
+    }
+}
+
+// In-memory volumes, handy for small images and for exercising the parser without a disk
+impl<T: AsRef<[u8]>> VolumeSource for Cursor<T> {
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn length(&self) -> u64 {
+        self.get_ref().as_ref().len() as u64
+    }
+}
+
+impl<T: VolumeSource + ?Sized> VolumeSource for Box<T> {
+    fn sector_size(&self) -> u32 {
+        (**self).sector_size()
+    }
+
+    fn length(&self) -> u64 {
+        (**self).length()
+    }
+
+    fn read_ahead(&mut self, extents: &[Range<u64>]) {
+        (**self).read_ahead(extents)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+}
+
+impl<R: VolumeSource + ?Sized> VolumeSource for &mut R {
+    fn sector_size(&self) -> u32 {
+        (**self).sector_size()
+    }
+
+    fn length(&self) -> u64 {
+        (**self).length()
+    }
+
+    fn read_ahead(&mut self, extents: &[Range<u64>]) {
+        (**self).read_ahead(extents)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+}
+
+// Returns the drive letter if `path` names a mounted volume (`C:`, `C:\` or `\\.\C:`) rather than a file
+pub fn parse_drive_letter(path: &str) -> Option<char> {
+    let path = path.strip_prefix(r"\\.\").unwrap_or(path);
+    let mut chars = path.chars();
+    let drive_letter = chars.next()?;
+
+    if !drive_letter.is_ascii_alphabetic() || chars.next()? != ':' {
+        return None;
+    }
+
+    match chars.as_str() {
+        "" | "\\" | "/" => Some(drive_letter.to_ascii_uppercase()),
+        _ => None,
+    }
+}
+
+// Opens whatever `path` refers to as a volume source: a drive letter or Win32 device path opens the
+// live volume, `.vhd`/`.vhdx` files open the virtual disk inside them, a numbered segment (`disk.001`)
+// opens the whole split image, and anything else is treated as a raw image file
+pub fn open_volume(path: &str) -> Result<Box<dyn VolumeSource + Send>> {
+    let device_path = match parse_drive_letter(path) {
+        Some(drive_letter) => Some(format!(r"\\.\{}:", drive_letter)),
+        None if path.starts_with(r"\\.\") => Some(path.to_string()),
+        None => None,
+    };
+
+    let extension = std::path::Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+    match device_path {
+        #[cfg(windows)]
+        Some(device_path) => Ok(Box::new(crate::VolumeReader::open_path(&device_path)?)),
+        #[cfg(not(windows))]
+        Some(device_path) => anyhow::bail!("Cannot open \"{}\": raw volume access is only supported on Windows", device_path),
+        None if extension.as_deref() == Some("vhd") => Ok(Box::new(VhdReader::open_path(path)?)),
+        None if extension.as_deref() == Some("vhdx") => Ok(Box::new(VhdxReader::open_path(path)?)),
+        None if segment_number(std::path::Path::new(path)).is_some() => {
+            Ok(Box::new(SegmentedImageReader::open_first_segment(path)?))
+        }
+        None => Ok(Box::new(ImageReader::open_path(path)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An in-memory device that checks reads are sector aligned and records where they were
+    struct TestDevice {
+        data: Vec<u8>,
+        sector_size: u32,
+        reads: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl SectorDevice for TestDevice {
+        fn sector_size(&self) -> u32 {
+            self.sector_size
+        }
+
+        fn length(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            assert_eq!(offset % self.sector_size as u64, 0);
+            assert_eq!(buf.len() % self.sector_size as usize, 0);
+            self.reads.lock().unwrap().push(offset);
+
+            let start = (offset as usize).min(self.data.len());
+            let n = buf.len().min(self.data.len() - start);
+            buf[..n].copy_from_slice(&self.data[start..start + n]);
+            Ok(n)
+        }
+    }
+
+    fn test_reader(length: usize, sector_size: u32) -> (SectorReader<TestDevice>, Arc<Mutex<Vec<u64>>>) {
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let device = TestDevice {
+            data: (0..length).map(|i| (i % 251) as u8).collect(),
+            sector_size,
+            reads: reads.clone(),
+        };
+        (SectorReader::new(device), reads)
+    }
+
+    fn expected(range: Range<usize>) -> Vec<u8> {
+        range.map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn unaligned_reads() {
+        let (mut reader, _) = test_reader(3 * CACHE_BLOCK_SIZE, 4096);
+
+        // Within a block, and across a block boundary
+        let mut buf = vec![0u8; 100];
+        reader.seek(SeekFrom::Start(7)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected(7..107));
+
+        let start = CACHE_BLOCK_SIZE - 50;
+        reader.seek(SeekFrom::Start(start as u64)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected(start..start + 100));
+
+        let stats = reader.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+    }
+
+    #[test]
+    fn reads_stop_at_end_of_volume() {
+        // Not a whole number of sectors, so the last sector is short
+        let length = 2 * CACHE_BLOCK_SIZE + 1000;
+        let (mut reader, _) = test_reader(length, 512);
+
+        let mut buf = vec![0u8; 4096];
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], expected(length - 10..length));
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        // Large reads go around the cache but still stop at the end
+        let mut buf = vec![0u8; 3 * CACHE_BLOCK_SIZE];
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), length);
+        assert_eq!(buf[..length], expected(0..length));
+
+        assert!(reader.seek(SeekFrom::Current(-(length as i64) - 1)).is_err());
+    }
+
+    #[test]
+    fn read_ahead_blocks_are_read_once() {
+        let (mut reader, reads) = test_reader(8 * CACHE_BLOCK_SIZE, 512);
+        reader.read_ahead(&[0..4 * CACHE_BLOCK_SIZE as u64, 4 * CACHE_BLOCK_SIZE as u64..8 * CACHE_BLOCK_SIZE as u64]);
+
+        // Whole block reads that would otherwise go straight to the device
+        let mut buf = vec![0u8; 8 * CACHE_BLOCK_SIZE];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected(0..8 * CACHE_BLOCK_SIZE));
+
+        let mut reads = reads.lock().unwrap().clone();
+        reads.sort_unstable();
+        let blocks: Vec<u64> = (0..8).map(|b| b * CACHE_BLOCK_SIZE as u64).collect();
+        assert_eq!(reads, blocks);
+        assert_eq!(reader.cache_stats().read_ahead_blocks, 8);
+    }
+
+    #[test]
+    fn drive_letters() {
+        assert_eq!(parse_drive_letter("c:"), Some('C'));
+        assert_eq!(parse_drive_letter(r"D:\"), Some('D'));
+        assert_eq!(parse_drive_letter(r"\\.\E:"), Some('E'));
+        assert_eq!(parse_drive_letter(r"C:\image.vhd"), None);
+        assert_eq!(parse_drive_letter("disk.img"), None);
+        assert_eq!(parse_drive_letter(r"\\.\PhysicalDrive0"), None);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{ffi::c_void, io, mem::size_of};
+use anyhow::{bail, Result};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{CloseHandle, GENERIC_READ, HANDLE};
+use windows::Win32::Storage::FileSystem::{CreateFileW, FILE_BEGIN, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE, FILE_TYPE_DISK, GetFileType, OPEN_EXISTING, ReadFile, SetFilePointerEx};
+use windows::Win32::System::IO::DeviceIoControl;
+use windows::Win32::System::Ioctl::{DISK_GEOMETRY, GET_LENGTH_INFORMATION, IOCTL_DISK_GET_DRIVE_GEOMETRY, IOCTL_DISK_GET_LENGTH_INFO};
+use crate::boot::{BootSector, MAX_SECTOR_SIZE};
+use crate::volume::{SectorDevice, SectorReader};
+
+// Win32 only handles disk IO that is sector aligned and operates on whole sectors
+pub type VolumeReader = SectorReader<Win32Volume>;
+
+pub struct Win32Volume {
+    pub handle: HANDLE,
+    // The source of truth for geometry. None for whole disks, which don't start with an NTFS boot sector.
+    pub boot_sector: Option<BootSector>,
+    pub sector_size: u32,
+    pub length: u64,
+}
+
+impl Win32Volume {
+    pub fn from_raw_handle(handle: HANDLE) -> Result<Self> {
+        // Owning the handle from here on means it gets closed on every error path
+        let mut volume = Win32Volume {
+            handle,
+            boot_sector: None,
+            sector_size: MAX_SECTOR_SIZE as u32,
+            length: 0,
+        };
+
+        // Where there is a boot sector this only ever raises the alignment, as IOCTL_DISK_GET_DRIVE_GEOMETRY fails or
+        // lies for dynamic disks, storage spaces and 4Kn devices
+        let ioctl_sector_size;
+        unsafe {
+            if GetFileType(handle) != FILE_TYPE_DISK {
+                bail!("Handle does not refer to a disk or volume");
+            }
+
+            ioctl_sector_size = device_io_control::<DISK_GEOMETRY>(handle, IOCTL_DISK_GET_DRIVE_GEOMETRY)
+                .ok()
+                .map(|g| g.BytesPerSector);
+            // SetFilePointerEx(FILE_END) doesn't report the size of a volume or disk handle
+            volume.length = device_io_control::<GET_LENGTH_INFORMATION>(handle, IOCTL_DISK_GET_LENGTH_INFO)
+                .map_or(0, |l| l.Length as u64);
+        }
+
+        // One maximum size sector is a whole number of sectors for every sector size NTFS supports
+        let mut buf = vec![0u8; MAX_SECTOR_SIZE];
+        if let Ok(n) = volume.read_sectors(0, &mut buf) {
+            volume.boot_sector = BootSector::parse(&buf[..n]).ok();
+        }
+
+        volume.sector_size = match (volume.boot_sector, ioctl_sector_size) {
+            // Both are powers of two, so aligning to the larger satisfies the filesystem and the device
+            (Some(boot_sector), Some(ioctl_sector_size)) => boot_sector.bytes_per_sector.max(ioctl_sector_size),
+            (Some(boot_sector), None) => boot_sector.bytes_per_sector,
+            (None, Some(ioctl_sector_size)) => ioctl_sector_size,
+            (None, None) => bail!("Could not determine the sector size: no NTFS boot sector and no drive geometry"),
+        };
+
+        if volume.length == 0 {
+            volume.length = match volume.boot_sector {
+                // The backup boot sector sits in the sector after the last one counted by the boot sector
+                Some(boot_sector) => boot_sector.volume_size() + boot_sector.bytes_per_sector as u64,
+                None => bail!("Could not determine the length of the disk"),
+            };
+        }
+
+        Ok(volume)
+    }
+
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut path: Vec<u16> = path.encode_utf16().collect();
+        path.push(0);
+
+        unsafe {
+            let disk_handle = CreateFileW(
+                PCWSTR::from_raw(path.as_ptr()),
+                GENERIC_READ.0,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                None,
+                OPEN_EXISTING,
+                FILE_FLAGS_AND_ATTRIBUTES(0),
+                None,
+            )?;
+
+            Win32Volume::from_raw_handle(disk_handle)
+        }
+    }
+}
+
+unsafe fn device_io_control<T: Default>(handle: HANDLE, control_code: u32) -> Result<T> {
+    let mut out = T::default();
+    let mut bytes_returned = 0u32;
+    DeviceIoControl(
+        handle,
+        control_code,
+        None,
+        0,
+        Some(&mut out as *mut _ as *mut c_void),
+        size_of::<T>() as u32,
+        Some(&mut bytes_returned),
+        None,
+    )?;
+
+    Ok(out)
+}
+
+impl SectorDevice for Win32Volume {
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut bytes_read = 0u32;
+        unsafe {
+            SetFilePointerEx(self.handle, offset as i64, None, FILE_BEGIN)?;
+            ReadFile(self.handle, Some(buf), Some(&mut bytes_read), None)?;
+        }
+
+        Ok(bytes_read as usize)
+    }
+}
+
+impl Drop for Win32Volume {
+    fn drop(&mut self) {
+        unsafe {
+            // Nothing useful can be done about a handle that fails to close
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+impl VolumeReader {
+    pub fn from_raw_handle(handle: HANDLE) -> Result<Self> {
+        Ok(SectorReader::new(Win32Volume::from_raw_handle(handle)?))
+    }
+
+    pub fn open_path(path: &str) -> Result<Self> {
+        Ok(SectorReader::new(Win32Volume::open_path(path)?))
+    }
+}